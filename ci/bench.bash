@@ -0,0 +1,141 @@
+#!/usr/bin/env bash
+# Script for benchmarking graph construction and tracing on synthetic instances.
+# Runs `gcm137 merge` with `--run-report` and collects the wall-clock time, the stage timings and
+# the peak memory, comparing them side by side when given two executables.
+set -e
+
+source ci/common.bash
+
+# $1 {path} = Directory for the instances, run reports and summary
+OUTDIR=$1
+# $2.. {path} = gcm137 executables to compare, e.g. release builds of two revisions
+shift || true
+BINARIES=("$@")
+
+required_arg "$OUTDIR" 'OUTDIR'
+required_arg "${BINARIES[0]}" '<gcm137 executable>'
+
+# name:constraints:taxa per constraint:columns:glues, overridable with BENCH_INSTANCES
+INSTANCES=${BENCH_INSTANCES:-"k10:10:20:500:10 k100:100:5:300:10 k1000:1000:3:200:5"}
+# merge options of each run, separated by commas, overridable with BENCH_RUNS
+RUNS=${BENCH_RUNS:-"-t upgma,-t upgma --low-memory,-t mcl,-t rg"}
+SEED=${BENCH_SEED:-42}
+
+# Writes `constraints` subset alignments and `glues` glue alignments (one random taxon per
+# subset) induced by a random true alignment, dropping the columns gapped in all their taxa.
+generate() {
+    local dir=$1 constraints=$2 taxa=$3 columns=$4 glues=$5
+    mkdir -p "$dir"
+    awk -v K="$constraints" -v T="$taxa" -v L="$columns" -v G="$glues" -v seed="$SEED" \
+        -v dir="$dir" '
+    function write(file, members, count,    j, m, keep, row) {
+        for (j = 1; j <= L; j++) {
+            keep[j] = 0
+            for (m = 0; m < count && !keep[j]; m++) {
+                keep[j] = substr(seqs[members[m]], j, 1) != "-"
+            }
+        }
+        for (m = 0; m < count; m++) {
+            row = ""
+            for (j = 1; j <= L; j++) {
+                if (keep[j]) {
+                    row = row substr(seqs[members[m]], j, 1)
+                }
+            }
+            print ">t" members[m] > file
+            print row > file
+        }
+        close(file)
+    }
+    BEGIN {
+        srand(seed)
+        for (t = 0; t < K * T; t++) {
+            s = ""
+            for (j = 0; j < L; j++) {
+                s = s (rand() < 0.3 ? "-" : substr("ACGT", int(rand() * 4) + 1, 1))
+            }
+            seqs[t] = s
+        }
+        for (c = 0; c < K; c++) {
+            for (m = 0; m < T; m++) {
+                members[m] = c * T + m
+            }
+            write(sprintf("%s/constraint_%04d.fa", dir, c), members, T)
+        }
+        for (g = 0; g < G; g++) {
+            for (c = 0; c < K; c++) {
+                members[c] = c * T + int(rand() * T)
+            }
+            write(sprintf("%s/glue_%04d.fa", dir, g), members, K)
+        }
+    }'
+}
+
+SUMMARY="$OUTDIR/summary.tsv"
+mkdir -p "$OUTDIR"
+printf "binary\tinstance\trun\tmetric\tvalue\n" > "$SUMMARY"
+for instance in $INSTANCES; do
+    IFS=: read -r name constraints taxa columns glues <<< "$instance"
+    dir="$OUTDIR/$name"
+    if [ ! -d "$dir" ]; then
+        echo "Generating $name ($constraints subsets of $taxa taxa, $columns columns, $glues glues)" >&2
+        generate "$dir" "$constraints" "$taxa" "$columns" "$glues"
+    fi
+    IFS=, read -r -a runs <<< "$RUNS"
+    for bin in "${BINARIES[@]}"; do
+        # revisions older than --run-report only get their wall-clock time measured
+        report_args=()
+        if "$bin" merge --help | grep -q -- --run-report; then
+            report_args=(--run-report "$dir/run.tsv")
+        fi
+        for run in "${runs[@]}"; do
+            rm -f "$dir/run.tsv"
+            echo "Running $bin merge $run on $name" >&2
+            start=$(date +%s.%N)
+            # shellcheck disable=SC2086
+            if ! "$bin" merge -i "$dir"/constraint_*.fa -g "$dir"/glue_*.fa $run \
+                "${report_args[@]}" -o "$dir/merged.fa"; then
+                echo "Skipping $run on $name: $bin failed" >&2
+                continue
+            fi
+            end=$(date +%s.%N)
+            printf "%s\t%s\t%s\twall.seconds\t%s\n" "$bin" "$name" "$run" \
+                "$(awk -v s="$start" -v e="$end" 'BEGIN { printf "%.3f", e - s }')" >> "$SUMMARY"
+            if [ ! -f "$dir/run.tsv" ]; then
+                continue
+            fi
+            # the glue subgraphs are built in parallel, so their times are summed
+            awk -F '\t' -v bin="$bin" -v instance="$name" -v run="$run" '
+                NR == 1 { next }
+                $1 ~ /^subgraph .*\.seconds$/ { subgraphs += $2; next }
+                $1 ~ /\.peak_rss_kb$/ { if ($2 > peak) peak = $2; next }
+                { printf "%s\t%s\t%s\t%s\t%s\n", bin, instance, run, $1, $2 }
+                END {
+                    printf "%s\t%s\t%s\tsubgraphs.seconds\t%s\n", bin, instance, run, subgraphs
+                    printf "%s\t%s\t%s\tpeak_rss_kb\t%s\n", bin, instance, run, peak
+                }' "$dir/run.tsv" >> "$SUMMARY"
+        done
+    done
+done
+cat "$SUMMARY"
+
+# with two executables, put the metrics measured by both side by side, the ratio being
+# before / after (above 1 when the second executable is faster or smaller)
+if [ "${#BINARIES[@]}" -eq 2 ]; then
+    COMPARISON="$OUTDIR/comparison.tsv"
+    awk -F '\t' -v before="${BINARIES[0]}" -v after="${BINARIES[1]}" '
+        NR == 1 { print "instance\trun\tmetric\tbefore\tafter\tratio"; next }
+        $1 == before { b[$2 "\t" $3 "\t" $4] = $5 }
+        $1 == after { a[$2 "\t" $3 "\t" $4] = $5; keys[++n] = $2 "\t" $3 "\t" $4 }
+        END {
+            for (i = 1; i <= n; i++) {
+                k = keys[i]
+                if (k in b) {
+                    ratio = a[k] > 0 ? sprintf("%.2f", b[k] / a[k]) : "-"
+                    printf "%s\t%s\t%s\t%s\n", k, b[k], a[k], ratio
+                }
+            }
+        }' "$SUMMARY" > "$COMPARISON"
+    echo
+    cat "$COMPARISON"
+fi
@@ -0,0 +1,235 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::bail;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{error::GcmError, merge::state_from_constraints, state::AlnState};
+
+/// Accuracy of an estimated alignment against a reference, as reported by FastSP. Homologies
+/// are the pairs of residues sharing a column; the ratios are 1 when there is nothing to count.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccuracyScore {
+    pub taxa: usize,
+    /// homologies of the reference
+    pub reference_homologies: u64,
+    /// homologies of the estimated alignment
+    pub estimated_homologies: u64,
+    /// homologies found in both alignments
+    pub shared_homologies: u64,
+    /// reference columns with at least two residues
+    pub reference_columns: usize,
+    /// of these, the columns found with exactly the same residues in the estimated alignment
+    pub shared_columns: usize,
+    /// fraction of the reference homologies recovered
+    pub sp_score: f64,
+    /// fraction of the estimated homologies that are in the reference
+    pub modeler: f64,
+    /// total column score, fraction of the reference columns recovered
+    pub tc: f64,
+    pub spfn: f64,
+    pub spfp: f64,
+}
+
+impl AccuracyScore {
+    pub fn log(&self) {
+        info!(
+            "SP-score {:.4}, modeler {:.4}, TC {:.4} ({} shared homologies of {} in the reference \
+             and {} estimated; {} of {} columns)",
+            self.sp_score,
+            self.modeler,
+            self.tc,
+            self.shared_homologies,
+            self.reference_homologies,
+            self.estimated_homologies,
+            self.shared_columns,
+            self.reference_columns
+        );
+    }
+
+    /// Writes the scores as `metric<TAB>value` rows.
+    pub fn print(&self) {
+        println!("taxa\t{}", self.taxa);
+        println!("sp_score\t{}", self.sp_score);
+        println!("modeler\t{}", self.modeler);
+        println!("spfn\t{}", self.spfn);
+        println!("spfp\t{}", self.spfp);
+        println!("tc\t{}", self.tc);
+        println!("reference_homologies\t{}", self.reference_homologies);
+        println!("estimated_homologies\t{}", self.estimated_homologies);
+        println!("shared_homologies\t{}", self.shared_homologies);
+        println!("reference_columns\t{}", self.reference_columns);
+        println!("shared_columns\t{}", self.shared_columns);
+    }
+}
+
+/// Reads one taxon name per line, ignoring blank lines.
+pub fn read_taxa(path: &PathBuf) -> anyhow::Result<AHashSet<String>> {
+    let io_err = |source| GcmError::Io {
+        file: path.clone(),
+        source,
+    };
+    let mut taxa = AHashSet::default();
+    for line in BufReader::new(File::open(path).map_err(io_err)?).lines() {
+        let line = line.map_err(io_err)?;
+        let name = line.trim();
+        if !name.is_empty() {
+            taxa.insert(name.to_string());
+        }
+    }
+    Ok(taxa)
+}
+
+/// Compares an estimated alignment to a reference on the taxa of `taxa` (by default, all the
+/// taxa of the reference), which must have the same residues in both alignments.
+pub fn score_alignments(
+    reference: &PathBuf,
+    estimated: &PathBuf,
+    taxa: Option<&AHashSet<String>>,
+) -> anyhow::Result<AccuracyScore> {
+    let reference = state_from_constraints(&[reference.clone()], true)?;
+    let estimated = state_from_constraints(&[estimated.clone()], true)?;
+    let included = |name: &str| taxa.map_or(true, |taxa| taxa.contains(name));
+    if taxa.is_none() {
+        if let Some(name) = estimated
+            .names
+            .iter()
+            .find(|&name| !reference.names2id.contains_key(name))
+        {
+            bail!(
+                "taxon {:?} of the estimated alignment is not in the reference",
+                name
+            );
+        }
+    }
+    // the reference and estimated columns of every residue
+    let mut columns: Vec<(u32, u32)> = vec![];
+    let mut count = 0;
+    for (r, name) in reference.names.iter().enumerate() {
+        if !included(name) {
+            continue;
+        }
+        let e = match estimated.names2id.get(name) {
+            Some(&e) => e,
+            None => bail!(
+                "taxon {:?} of the reference is not in the estimated alignment",
+                name
+            ),
+        };
+        if reference.residues[r] != estimated.residues[e] {
+            bail!(
+                "taxon {:?} has different residues in the two alignments",
+                name
+            );
+        }
+        columns.extend(
+            reference.s[r]
+                .iter()
+                .zip(&estimated.s[e])
+                .map(|(&(_, i), &(_, j))| (i, j)),
+        );
+        count += 1;
+    }
+    if let Some(taxa) = taxa {
+        if let Some(name) = taxa
+            .iter()
+            .find(|&name| !reference.names2id.contains_key(name))
+        {
+            bail!("taxon {:?} is not in the reference", name);
+        }
+    }
+    Ok(score_columns(&reference, &estimated, columns, count))
+}
+
+/// Counts the homologies from the pairs of reference and estimated columns of the residues.
+fn score_columns(
+    reference: &AlnState,
+    estimated: &AlnState,
+    mut columns: Vec<(u32, u32)>,
+    taxa: usize,
+) -> AccuracyScore {
+    let pairs = |n: usize| (n * n.saturating_sub(1) / 2) as u64;
+    let mut reference_sizes = vec![0usize; reference.column_counts[0]];
+    let mut estimated_sizes = vec![0usize; estimated.column_counts[0]];
+    for &(i, j) in &columns {
+        reference_sizes[i as usize] += 1;
+        estimated_sizes[j as usize] += 1;
+    }
+    columns.sort_unstable();
+    let mut shared_homologies = 0;
+    let mut shared_columns = 0;
+    // runs of residues sharing both columns
+    let mut start = 0;
+    while start < columns.len() {
+        let mut end = start + 1;
+        while end < columns.len() && columns[end] == columns[start] {
+            end += 1;
+        }
+        let (i, j) = columns[start];
+        let n = end - start;
+        shared_homologies += pairs(n);
+        if n > 1 && n == reference_sizes[i as usize] && n == estimated_sizes[j as usize] {
+            shared_columns += 1;
+        }
+        start = end;
+    }
+    let reference_homologies: u64 = reference_sizes.iter().map(|&n| pairs(n)).sum();
+    let estimated_homologies: u64 = estimated_sizes.iter().map(|&n| pairs(n)).sum();
+    let reference_columns = reference_sizes.iter().filter(|&&n| n > 1).count();
+    let ratio = |x: u64, total: u64| {
+        if total == 0 {
+            1.0
+        } else {
+            x as f64 / total as f64
+        }
+    };
+    let sp_score = ratio(shared_homologies, reference_homologies);
+    let modeler = ratio(shared_homologies, estimated_homologies);
+    AccuracyScore {
+        taxa,
+        reference_homologies,
+        estimated_homologies,
+        shared_homologies,
+        reference_columns,
+        shared_columns,
+        sp_score,
+        modeler,
+        tc: ratio(shared_columns as u64, reference_columns as u64),
+        spfn: 1.0 - sp_score,
+        spfp: 1.0 - modeler,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_columns_by_hand() {
+        // reference      estimated
+        //   a XY           a XY-
+        //   b XY           b X-Y
+        //   c X-           c X--
+        let mut reference = AlnState::new();
+        reference.column_counts = vec![2];
+        let mut estimated = AlnState::new();
+        estimated.column_counts = vec![3];
+        let columns = vec![(0, 0), (1, 1), (0, 0), (1, 2), (0, 0)];
+        let score = score_columns(&reference, &estimated, columns, 3);
+        assert_eq!(score.reference_homologies, 4);
+        assert_eq!(score.estimated_homologies, 3);
+        assert_eq!(score.shared_homologies, 3);
+        assert_eq!(score.reference_columns, 2);
+        assert_eq!(score.shared_columns, 1);
+        assert_eq!(score.sp_score, 0.75);
+        assert_eq!(score.modeler, 1.0);
+        assert_eq!(score.tc, 0.5);
+        assert_eq!(score.spfn, 0.25);
+        assert_eq!(score.spfp, 0.0);
+    }
+}
@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 
-use seq_io::fasta::{Reader, RefRecord};
+use anyhow::Context;
+use seq_io::fasta::RefRecord;
+
+use crate::input::open_fasta;
 
 pub trait AlnProcessor {
     type Output;
@@ -8,14 +11,19 @@ pub trait AlnProcessor {
     fn take(&mut self) -> Self::Output;
 }
 
+/// Number of lines spanned by a record, including its header.
+pub fn record_lines(record: &RefRecord) -> usize {
+    1 + record.seq_lines().count()
+}
+
 pub fn process_aln2<A: AlnProcessor + Default, B: AlnProcessor + Default>(
     infile: &PathBuf,
 ) -> anyhow::Result<(A::Output, B::Output)> {
     let mut p1 = A::default();
     let mut p2 = B::default();
-    let mut reader = Reader::from_path(infile)?;
+    let mut reader = open_fasta(infile)?;
     while let Some(s) = reader.next() {
-        let r = s?;
+        let r = s.with_context(|| format!("reading {}", infile.display()))?;
         p1.on_record(&r)?;
         p2.on_record(&r)?;
     }
@@ -24,9 +32,9 @@ pub fn process_aln2<A: AlnProcessor + Default, B: AlnProcessor + Default>(
 
 pub fn process_aln1<A: AlnProcessor + Default>(infile: &PathBuf) -> anyhow::Result<A::Output> {
     let mut p = A::default();
-    let mut reader = Reader::from_path(infile)?;
+    let mut reader = open_fasta(infile)?;
     while let Some(s) = reader.next() {
-        let r = s?;
+        let r = s.with_context(|| format!("reading {}", infile.display()))?;
         p.on_record(&r)?;
     }
     Ok(p.take())
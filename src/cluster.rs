@@ -1,50 +1,184 @@
-use ahash::AHashMap;
 use clap::ArgEnum;
+use sprs::CsMat;
+
+use crate::verify::Violation;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug, Hash)]
 pub enum GCMStep {
     Auto,
     Upgma,
+    /// Smith-Waterman, progressively merging the constraints two at a time if more than two
     Pairwise,
+    /// Exact DP over all constraints, for small instances only
+    Exact,
+    /// Markov clustering, with UPGMA merging only within the MCL clusters
+    Mcl,
+    /// Greedy approximation of MAGUS MinClusters on the MCL clusters
+    MinClusters,
+    /// MAGUS FM conflict breaking of the MCL clusters
+    Fm,
+    /// MAGUS region growing on the MCL clusters
+    Rg,
+}
+
+/// How the UPGMA tracer computes the similarity of a merged cluster to another cluster,
+/// from the similarities of the two merged clusters to it (a missing edge meaning no similarity).
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug, Hash)]
+pub enum Linkage {
+    /// Average weighted by cluster sizes, ignoring missing edges
+    Average,
+    /// Average weighted by cluster sizes, missing edges counting as zero
+    AverageZeros,
+    /// Maximum similarity
+    Single,
+    /// Minimum similarity, dropping the edge if either is missing
+    Complete,
+    /// Total similarity
+    Sum,
+    /// Average of the two merged clusters' similarities regardless of their sizes (WPGMA),
+    /// missing edges counting as zero
+    Wpgma,
+}
+
+impl Linkage {
+    /// Similarity of the union of clusters of sizes `lsize` and `rsize`, with similarities `lhs`
+    /// and `rhs` to another cluster; `None` if they have no edge.
+    pub fn combine(
+        self,
+        lhs: Option<f64>,
+        rhs: Option<f64>,
+        lsize: usize,
+        rsize: usize,
+    ) -> Option<f64> {
+        let (l, r) = (lsize as f64, rsize as f64);
+        match (self, lhs, rhs) {
+            (_, None, None) => None,
+            (Linkage::Average, Some(v), None) | (Linkage::Average, None, Some(v)) => Some(v),
+            (Linkage::Complete, Some(v1), Some(v2)) => Some(v1.min(v2)),
+            (Linkage::Complete, _, _) => None,
+            (_, v1, v2) => {
+                let (v1, v2) = (v1.unwrap_or(0.0), v2.unwrap_or(0.0));
+                Some(match self {
+                    Linkage::Average | Linkage::AverageZeros => (v1 * l + v2 * r) / (l + r),
+                    Linkage::Single => v1.max(v2),
+                    Linkage::Sum => v1 + v2,
+                    Linkage::Wpgma => (v1 + v2) / 2.0,
+                    Linkage::Complete => unreachable!(),
+                })
+            }
+        }
+    }
 }
 
 pub struct Graph {
     pub size: usize,
+    /// nodes with at least one edge, in increasing order
     pub labels: Vec<usize>,
-    pub sims: AHashMap<usize, AHashMap<usize, f64>>,
+    /// symmetric similarity matrix in CSR, with both triangles stored
+    pub sims: CsMat<f64>,
     pub node_pos: Vec<(u32, u32)>,
 }
 
+impl Graph {
+    /// Builds the graph from the upper triangle of the similarity matrix.
+    pub fn from_upper(upper: &CsMat<f64>, node_pos: Vec<(u32, u32)>) -> Self {
+        let sims: CsMat<f64> = upper + &upper.transpose_view();
+        let labels = sims
+            .outer_iterator()
+            .enumerate()
+            .filter(|(_, row)| row.nnz() > 0)
+            .map(|(u, _)| u)
+            .collect();
+        Self {
+            size: node_pos.len(),
+            labels,
+            sims,
+            node_pos,
+        }
+    }
+
+    /// Weight of the edge between `u` and `v`, zero if absent.
+    pub fn weight(&self, u: usize, v: usize) -> f64 {
+        self.sims.get(u, v).copied().unwrap_or(0.0)
+    }
+
+    /// Neighbors of `u` along with the edge weights, in increasing order.
+    pub fn neighbors(&self, u: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let range = self.sims.indptr().outer_inds_sz(u);
+        self.sims.indices()[range.clone()]
+            .iter()
+            .copied()
+            .zip(self.sims.data()[range].iter().copied())
+    }
+
+    /// Every edge `(u, v, w)` once, with `u < v`.
+    pub fn edges(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+        self.sims
+            .iter()
+            .filter(|(_, (u, v))| u < v)
+            .map(|(&w, (u, v))| (u, v, w))
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.sims.nnz() / 2
+    }
+}
+
 #[derive(Debug)]
 pub struct ClusteringResult {
     pub clusters: Vec<Vec<(u32, u32)>>,
 }
 
 impl ClusteringResult {
-    pub fn check_validity(&self) {
-        for i in 0..self.clusters.len() {
-            for j in i..self.clusters.len() {
-                if i == j {
-                    continue;
-                }
-                let cond = Self::all_smaller(&self.clusters[i], &self.clusters[j])
-                    || Self::all_smaller(&self.clusters[j], &self.clusters[i]);
-                if !cond {
-                    println!("{:?} {:?}", &self.clusters[i], &self.clusters[j]);
-                    panic!("Clusters are not ordered");
+    /// Checks that the clusters, in the order of the trace, align the columns of constraints
+    /// with `column_counts` columns: each constraint must have its clustered columns in
+    /// increasing clusters. Runs in `O(n log n)` for `n` clustered columns, reporting at least
+    /// one violation for any invalid trace (one per pair of consecutive clustered columns).
+    pub fn violations(&self, column_counts: &[usize]) -> Vec<Violation> {
+        let mut violations = vec![];
+        let mut columns: Vec<(u32, u32, usize)> = vec![];
+        for (x, cluster) in self.clusters.iter().enumerate() {
+            for &(c, i) in cluster {
+                if column_counts
+                    .get(c as usize)
+                    .map_or(true, |&count| i as usize >= count)
+                {
+                    violations.push(Violation::OutOfRange {
+                        cluster: x,
+                        column: (c, i),
+                    });
+                } else {
+                    columns.push((c, i, x));
                 }
             }
         }
-    }
-
-    fn all_smaller(lhs: &[(u32, u32)], rhs: &[(u32, u32)]) -> bool {
-        // checks if the x-coordinate of lhs is all smaller than the x-coordinate of rhs
-        for (i, x) in lhs {
-            if rhs.iter().any(|(j, y)| *i == *j && *x > *y) {
-                return false;
+        columns.sort_unstable();
+        for pair in columns.windows(2) {
+            let ((c, i, x), (d, j, y)) = (pair[0], pair[1]);
+            if c != d {
+                continue;
+            }
+            if i == j {
+                violations.push(Violation::Repeated {
+                    constraint: c,
+                    column: i,
+                    clusters: (x, y),
+                });
+            } else if x == y {
+                violations.push(Violation::Duplicate {
+                    cluster: x,
+                    constraint: c,
+                    columns: (i, j),
+                });
+            } else if x > y {
+                violations.push(Violation::Crossing {
+                    constraint: c,
+                    columns: (i, j),
+                    clusters: (x, y),
+                });
             }
         }
-        true
+        violations
     }
 }
 
@@ -1,94 +1,415 @@
-use crate::{cluster::GCMStep, exact_solver::sw_algorithm};
-use anyhow::Ok;
+use crate::{
+    cluster::{ClusteringResult, GCMStep, Graph, Linkage},
+    error::GcmError,
+    exact_solver::{exact_mwt, exact_work, sw_algorithm, EXACT_MAX_CONSTRAINTS},
+    external::Aligner,
+    state::AlnState,
+};
+use anyhow::{anyhow, bail, Context, Ok};
+use itertools::Itertools;
 use ogcat::ogtree::{self, TreeCollection};
 use ordered_float::NotNan;
 use rand::prelude::SliceRandom;
-use seq_io::{
-    fasta::{OwnedRecord, Reader},
-    BaseRecord,
-};
+use seq_io::{fasta::OwnedRecord, BaseRecord};
 use std::{
     fs::create_dir_all,
     io::{BufWriter, Write},
     path::PathBuf,
+    sync::Arc,
 };
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 use crate::{
     aln::AlnProcessor,
+    input::open_fasta,
+    magus::{fm, min_clusters, region_growing},
+    mcl::{mcl, mcl_clusters},
     merge::{
-        build_frames, build_graph, merge_alignments_from_frames, state_from_constraints,
-        StateFromConstraints,
+        align_subsets, build_frames, build_graph, merge_alignments_from_frames,
+        state_from_constraints,
     },
-    naive_upgma::naive_upgma,
+    naive_upgma::{naive_upgma, upgma_within},
+    output::OutputFormat,
+    progressive::progressive_sw,
+    refine::{refine, RefineBudget},
+    report::{score_trace, write_json, ClusterStats, GraphStats, StageRecorder},
+    residues::ResidueMode,
     utils::SequenceSampler,
+    validate::validate_inputs,
+    verify::verify_trace,
 };
 
+/// Settings of the merging pipeline, besides its inputs and output path.
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    pub tracer: GCMStep,
+    /// linkage of the UPGMA tracer
+    pub linkage: Linkage,
+    /// inflation of the MCL tracer
+    pub inflation: f64,
+    pub residue_mode: ResidueMode,
+    /// build the graph reading one glue at a time
+    pub streaming: bool,
+    pub output_format: OutputFormat,
+    /// number of columns per output line, 0 for unwrapped
+    pub line_width: usize,
+    /// local search applied to the trace
+    pub refine: RefineBudget,
+    /// where to write the score of the trace as JSON
+    pub report: Option<PathBuf>,
+    /// where to write the stage timings and graph statistics, as TSV or JSON
+    pub run_report: Option<PathBuf>,
+    /// check the trace and the layout of the merged alignment before writing it
+    pub verify: bool,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            tracer: GCMStep::Auto,
+            linkage: Linkage::Average,
+            inflation: 4.0,
+            residue_mode: ResidueMode::Trust,
+            streaming: false,
+            output_format: OutputFormat::Fasta,
+            line_width: 60,
+            refine: RefineBudget::default(),
+            report: None,
+            run_report: None,
+            verify: false,
+        }
+    }
+}
+
 #[tracing::instrument]
 pub fn oneshot_merge_alignments(
     constraints: &[PathBuf],
     glues: &[PathBuf],
-    tracer_mode: GCMStep,
     weights: &Option<Vec<NotNan<f64>>>,
+    options: &MergeOptions,
     outpath: &PathBuf,
 ) -> anyhow::Result<()> {
-    let mut state = state_from_constraints(constraints)?;
+    let stages = StageRecorder::new();
+    let report = stages.time("validate", || validate_inputs(constraints, glues, weights))?;
+    if !report.is_ok() {
+        return Err(report.into());
+    }
+    debug!("Validated inputs");
+    let state = stages.time("state", || {
+        state_from_constraints(constraints, options.residue_mode.compares_residues())
+    })?;
     debug!("Constructed state from constraints");
-    let graph = build_graph(&mut state, glues, weights).unwrap();
+    let graph = build_graph(
+        &state,
+        glues,
+        weights,
+        options.residue_mode,
+        options.streaming,
+        &stages,
+    )?;
     debug!("Built alignment graph.");
-    let res = if constraints.len() == 2 && tracer_mode != GCMStep::Upgma {
-        debug!("Running Smith-Waterman, solving MWT-AM exactly.");
-        sw_algorithm(&graph, &state)
-    } else {
-        debug!("Running UPGMA heuristic for solving MWT-AM.");
-        naive_upgma(&graph, &state)
-    };
+    let mut res = stages.time("trace", || trace(&graph, &state, options.tracer, options))?;
+    if options.refine.is_enabled() {
+        res = stages.time("refine", || refine(&graph, &state, res, &options.refine));
+        debug!("Refined the trace.");
+    }
     debug!("Clustered/Traced alignment graph.");
+    let score = score_trace(&graph, &state, &res, constraints);
+    score.log();
+    if let Some(path) = &options.report {
+        write_json(path, &score)?;
+    }
     let frames = build_frames(&state, &res);
-    debug!("Flushing merged alignments...");
-    merge_alignments_from_frames(constraints, &frames, outpath)?;
+    if options.verify {
+        let report = stages.time("verify", || verify_trace(&state, &res, &frames));
+        if !report.is_ok() {
+            return Err(GcmError::InvalidTrace(report).into());
+        }
+        info!("Verified the merged alignment against the subset alignments.");
+    } else {
+        // without --verify, the tracers are trusted to produce valid traces in release builds
+        debug_assert!(res.violations(&state.column_counts).is_empty());
+    }
+    stages.time("output", || {
+        debug!("Flushing merged alignments...");
+        merge_alignments_from_frames(
+            constraints,
+            &frames,
+            outpath,
+            options.output_format,
+            options.line_width,
+        )
+    })?;
+    if let Some(path) = &options.run_report {
+        let run = stages.finish(Some(GraphStats::new(&graph)), Some(ClusterStats::new(&res)));
+        run.write(path)?;
+    }
     Ok(())
 }
 
-pub fn oneshot_stitch_alignments(
+/// Solves MWT-AM on the alignment graph with the given tracer.
+fn trace(
+    graph: &Graph,
+    state: &AlnState,
+    tracer: GCMStep,
+    options: &MergeOptions,
+) -> anyhow::Result<ClusteringResult> {
+    let linkage = options.linkage;
+    if tracer == GCMStep::Auto {
+        let (chosen, rationale) = auto_tracer(graph, state);
+        info!("Automatically chose the {:?} tracer: {}", chosen, rationale);
+        return trace(graph, state, chosen, options);
+    }
+    if tracer == GCMStep::Exact {
+        debug!("Running the exact multi-way solver for MWT-AM.");
+        return exact_mwt(graph, state);
+    }
+    let k = state.column_counts.len();
+    let res = match tracer {
+        GCMStep::Pairwise if k > 2 => {
+            debug!("Running progressive Smith-Waterman for MWT-AM.");
+            progressive_sw(graph, state)
+        }
+        GCMStep::Pairwise if k == 2 => {
+            debug!("Running Smith-Waterman, solving MWT-AM exactly.");
+            sw_algorithm(graph, state)
+        }
+        GCMStep::Mcl => {
+            debug!("Running MCL with inflation {}.", options.inflation);
+            let groups = mcl(graph, options.inflation);
+            debug!(
+                "MCL found {} clusters; resolving them with UPGMA ({:?} linkage).",
+                groups.iter().max().map_or(0, |&g| g + 1),
+                linkage
+            );
+            upgma_within(graph, state, linkage, Some(&groups))
+        }
+        GCMStep::MinClusters | GCMStep::Fm | GCMStep::Rg => {
+            debug!("Running MCL with inflation {}.", options.inflation);
+            let clusters = mcl_clusters(graph, options.inflation);
+            debug!(
+                "MCL found {} clusters; resolving them with {:?}.",
+                clusters.clusters.len(),
+                tracer
+            );
+            match tracer {
+                GCMStep::MinClusters => min_clusters(graph, state, &clusters),
+                GCMStep::Fm => fm(graph, state, &clusters),
+                _ => region_growing(graph, state, &clusters),
+            }
+        }
+        _ => {
+            debug!(
+                "Running UPGMA heuristic ({:?} linkage) for solving MWT-AM.",
+                linkage
+            );
+            naive_upgma(graph, state, linkage)
+        }
+    };
+    Ok(res)
+}
+
+/// Largest work (frontier states times subsets of constraints) of the exact tracer under `Auto`,
+/// well below the limit of the exact tracer itself so that it stays fast
+const AUTO_EXACT_WORK: usize = 50_000_000;
+/// Largest number of constraints merged progressively under `Auto`
+const AUTO_PAIRWISE_MAX_CONSTRAINTS: usize = 8;
+/// Largest DP matrix of the last progressive merge under `Auto`
+const AUTO_PAIRWISE_MAX_CELLS: usize = 200_000_000;
+/// Largest mean number of neighbors per other constraint for merging progressively under `Auto`
+const AUTO_PAIRWISE_MAX_DEGREE: f64 = 2.0;
+
+/// Picks a tracer from the size and structure of the instance, explaining the choice.
+fn auto_tracer(graph: &Graph, state: &AlnState) -> (GCMStep, String) {
+    let k = state.column_counts.len();
+    if k == 2 {
+        return (
+            GCMStep::Pairwise,
+            "two constraints, where Smith-Waterman is exact".to_string(),
+        );
+    }
+    if k <= EXACT_MAX_CONSTRAINTS {
+        let work = exact_work(&state.column_counts);
+        if let Some(work) = work.filter(|&w| w <= AUTO_EXACT_WORK) {
+            return (
+                GCMStep::Exact,
+                format!(
+                    "{} constraints with {} frontier states by subsets of constraints, \
+                     small enough to be solved exactly",
+                    k, work
+                ),
+            );
+        }
+    }
+    let total: usize = state.column_counts.iter().sum();
+    let cells = (total / 2).saturating_mul(total - total / 2);
+    let degree = if graph.labels.is_empty() {
+        0.0
+    } else {
+        2.0 * graph.edge_count() as f64 / graph.labels.len() as f64 / (k - 1).max(1) as f64
+    };
+    if k <= AUTO_PAIRWISE_MAX_CONSTRAINTS
+        && cells <= AUTO_PAIRWISE_MAX_CELLS
+        && degree <= AUTO_PAIRWISE_MAX_DEGREE
+    {
+        return (
+            GCMStep::Pairwise,
+            format!(
+                "{} constraints with {} columns in total, and {:.2} neighbors per node and other constraint \
+                 on average, i.e., few conflicting edges for progressive Smith-Waterman",
+                k, total, degree
+            ),
+        );
+    }
+    (
+        GCMStep::Upgma,
+        format!(
+            "{} constraints with {} columns in total, {} edges and {:.2} neighbors per node and other constraint \
+             on average, too large or conflicting for the exact and progressive tracers",
+            k,
+            total,
+            graph.edge_count(),
+            degree
+        ),
+    )
+}
+
+/// Merges subset alignments without given glues: glues are built by sampling
+/// sequences from every subset, aligned with `aligner`, and then used for merging.
+/// The glues are written under `outdir`, and at most `tokens` aligner jobs run at once.
+#[allow(clippy::too_many_arguments)]
+pub async fn oneshot_stitch_alignments(
     constraints: &[PathBuf],
-    _outpath: &PathBuf,
+    glues: (usize, usize),
+    weights: &Option<Vec<NotNan<f64>>>,
+    options: &MergeOptions,
+    aligner: Arc<dyn Aligner>,
+    tokens: usize,
+    outdir: &PathBuf,
+    outpath: &PathBuf,
 ) -> anyhow::Result<()> {
-    let mut p = StateFromConstraints::default();
-    for aln in constraints {
-        // cid : constraint id
-        let mut s = SequenceSampler::new(Some(150 / constraints.len()));
-        let mut reader = Reader::from_path(aln)?;
+    let glue_paths = sample_glues(constraints, glues, outdir)?;
+    debug!("Sampled {} glues from constraints", glue_paths.len());
+    let glue_alns = align_subsets(&glue_paths, aligner, tokens).await?;
+    debug!("Aligned glues.");
+    oneshot_merge_alignments(constraints, &glue_alns, weights, options, outpath)
+}
+
+/// Samples `glue_num` unaligned glues of about `glue_size` sequences each, evenly drawn from every constraint.
+fn sample_glues(
+    constraints: &[PathBuf],
+    glues: (usize, usize),
+    outdir: &PathBuf,
+) -> anyhow::Result<Vec<PathBuf>> {
+    if constraints.is_empty() {
+        bail!("no constraint alignments to sample glues from");
+    }
+    let (glue_num, glue_size) = glues;
+    let sample_size = (glue_size / constraints.len()).max(1);
+    let mut samplers = (0..glue_num)
+        .map(|_| {
+            constraints
+                .iter()
+                .map(|_| SequenceSampler::new(Some(sample_size)))
+                .collect_vec()
+        })
+        .collect_vec();
+    for (i, aln) in constraints.iter().enumerate() {
+        let mut reader = open_fasta(aln)?;
         while let Some(result) = reader.next() {
-            let rec = result?;
-            p.on_record(&rec)?;
-            s.on_record(&rec)?;
+            let rec = result.with_context(|| format!("reading {}", aln.display()))?;
+            for glue in samplers.iter_mut() {
+                glue[i].on_record(&rec)?;
+            }
         }
-        p.next_aln();
     }
-    todo!(); // honestly I don't know if I should implement this or not
-    Ok(())
+    create_dir_all(outdir)?;
+    let mut glue_paths = vec![];
+    for (i, glue) in samplers.iter().enumerate() {
+        let mut gp = outdir.clone();
+        gp.push(format!("glue_{}.unaln.fa", i));
+        let mut writer = BufWriter::new(std::fs::File::create(&gp)?);
+        for sampler in glue {
+            sampler.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+        glue_paths.push(gp);
+    }
+    Ok(glue_paths)
 }
 
-pub fn oneshot_slice_sequences(
+/// Slices, aligns and merges unaligned sequences in one go.
+/// Intermediate files (unaligned and aligned subsets/glues) are kept under `outdir`,
+/// and at most `tokens` aligner jobs run at once.
+#[allow(clippy::too_many_arguments)]
+pub async fn oneshot_align_sequences(
     input: &PathBuf,
     tree: &PathBuf,
     glues: (usize, usize),
+    weights: &Option<Vec<NotNan<f64>>>,
     max_count: Option<usize>,
     max_size: Option<usize>,
+    options: &MergeOptions,
+    constraint_aligner: Arc<dyn Aligner>,
+    glue_aligner: Arc<dyn Aligner>,
+    tokens: usize,
     outdir: &PathBuf,
+    outpath: &PathBuf,
 ) -> anyhow::Result<()> {
+    let (constraints, glues) =
+        oneshot_slice_sequences(input, tree, glues, max_count, max_size, outdir)?;
+    debug!(
+        "Sliced sequences into {} constraints and {} glues",
+        constraints.len(),
+        glues.len()
+    );
+    let constraint_alns = align_subsets(&constraints, constraint_aligner, tokens).await?;
+    debug!("Aligned constraints.");
+    let glue_alns = align_subsets(&glues, glue_aligner, tokens).await?;
+    debug!("Aligned glues.");
+    oneshot_merge_alignments(&constraint_alns, &glue_alns, weights, options, outpath)
+}
+
+/// Slices unaligned sequences into subsets and glues, returning the paths
+/// of the written (unaligned) subsets and glues.
+pub fn oneshot_slice_sequences(
+    input: &PathBuf,
+    tree: &PathBuf,
+    glues: (usize, usize),
+    max_count: Option<usize>,
+    max_size: Option<usize>,
+    outdir: &PathBuf,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
     let mut rng = rand::thread_rng();
-    let collection = TreeCollection::from_newick(tree).expect("Failed to read tree");
-    let decomp = ogtree::centroid_edge_decomp(&collection.trees[0], &max_count, &max_size);
-    let labels = ogtree::cuts_to_subsets(&collection.trees[0], &decomp);
+    let collection = TreeCollection::from_newick(tree)
+        .map_err(|e| anyhow!("failed to read the tree {}: {:?}", tree.display(), e))?;
+    let first = collection
+        .trees
+        .first()
+        .ok_or_else(|| anyhow!("no tree found in {}", tree.display()))?;
+    let decomp = ogtree::centroid_edge_decomp(first, &max_count, &max_size);
+    if decomp.is_empty() {
+        bail!(
+            "the decomposition of the tree {} has no subsets",
+            tree.display()
+        );
+    }
+    let labels = ogtree::cuts_to_subsets(first, &decomp);
     let ts = &collection.taxon_set;
-    let mut reader = Reader::from_path(input)?;
+    let mut reader = open_fasta(input)?;
     let mut subsets: Vec<Vec<OwnedRecord>> = vec![Vec::new(); decomp.len()];
     while let Some(s) = reader.next() {
-        let r = s?;
+        let r = s.with_context(|| format!("reading {}", input.display()))?;
         let head = String::from_utf8_lossy(r.head());
-        let id = ts.retrieve(&head);
+        let id = match ts.to_id.get(&*head) {
+            Some(&id) => id,
+            None => bail!(
+                "sequence {:?} of {} is not a leaf of the tree {}",
+                head,
+                input.display(),
+                tree.display()
+            ),
+        };
         subsets[labels[id]].push(r.to_owned_record());
     }
     create_dir_all(outdir)?;
@@ -99,10 +420,13 @@ pub fn oneshot_slice_sequences(
     let mut glues_path = outdir.clone();
     glues_path.push("glues");
     create_dir_all(&glues_path)?; // oh my god this is so ugly
+    let mut constraint_paths = vec![];
+    let mut glue_paths = vec![];
     for (i, c) in subsets.iter().enumerate() {
         let mut cp = constraints_path.clone();
         cp.push(format!("constraint_{}.unaln.fa", i));
-        let file = std::fs::File::create(cp)?;
+        let file = std::fs::File::create(&cp)?;
+        constraint_paths.push(cp);
         let mut writer = BufWriter::new(file);
         for r in c {
             writer.write_all(b">")?;
@@ -117,7 +441,7 @@ pub fn oneshot_slice_sequences(
     }
     let k = decomp.len();
     let mut sample_size = glue_size / k;
-    let max_seqset_size = subsets.iter().map(|it| it.len()).max().unwrap();
+    let max_seqset_size = subsets.iter().map(|it| it.len()).max().unwrap_or(0);
     if sample_size > max_seqset_size {
         warn!(
             "Sample size is larger than largest subset size. Setting sample size to {}",
@@ -128,7 +452,8 @@ pub fn oneshot_slice_sequences(
     for i in 0..glue_num {
         let mut gp = glues_path.clone();
         gp.push(format!("glue_{}.unaln.fa", i));
-        let file = std::fs::File::create(gp)?;
+        let file = std::fs::File::create(&gp)?;
+        glue_paths.push(gp);
         let mut writer = BufWriter::new(file);
         for c in &subsets {
             c.choose_multiple(&mut rng, sample_size).try_for_each(|r| {
@@ -144,5 +469,5 @@ pub fn oneshot_slice_sequences(
             })?;
         }
     }
-    Ok(())
+    Ok((constraint_paths, glue_paths))
 }
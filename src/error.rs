@@ -0,0 +1,121 @@
+use std::{fmt, path::PathBuf};
+
+use crate::verify::VerifyReport;
+
+/// Errors in the inputs of the merging pipeline. `line` refers to the header line of the offending record.
+#[derive(Debug)]
+pub enum GcmError {
+    /// A glue contains a taxon absent from all constraints
+    UnknownTaxon {
+        name: String,
+        file: PathBuf,
+        line: usize,
+    },
+    /// A record whose length differs from the preceding records of the same alignment
+    RaggedAlignment {
+        name: String,
+        file: PathBuf,
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// A taxon appearing more than once across the constraints
+    DuplicateName {
+        name: String,
+        file: PathBuf,
+        line: usize,
+    },
+    /// A glue sequence whose residues differ from the same taxon in its constraint
+    ResidueMismatch {
+        name: String,
+        file: PathBuf,
+        line: usize,
+        /// 1-based index of the first differing residue
+        position: usize,
+        glue_residues: usize,
+        constraint_residues: usize,
+    },
+    /// The number of weights differs from the number of glues
+    WeightCountMismatch { weights: usize, glues: usize },
+    /// An input that cannot be parsed in its detected format
+    InvalidFormat { file: PathBuf, reason: String },
+    /// A trace that does not align the columns of the constraints in order
+    InvalidTrace(VerifyReport),
+    Io {
+        file: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl fmt::Display for GcmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GcmError::UnknownTaxon { name, file, line } => write!(
+                f,
+                "{}:{}: taxon {:?} is not found in any constraint alignment",
+                file.display(),
+                line,
+                name
+            ),
+            GcmError::RaggedAlignment {
+                name,
+                file,
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}:{}: sequence {:?} has {} columns, expected {} as in the preceding sequences",
+                file.display(),
+                line,
+                name,
+                found,
+                expected
+            ),
+            GcmError::DuplicateName { name, file, line } => write!(
+                f,
+                "{}:{}: taxon {:?} appears more than once across the constraint alignments",
+                file.display(),
+                line,
+                name
+            ),
+            GcmError::ResidueMismatch {
+                name,
+                file,
+                line,
+                position,
+                glue_residues,
+                constraint_residues,
+            } => write!(
+                f,
+                "{}:{}: residues of {:?} differ from its constraint sequence from residue {} on \
+                 ({} residues in the glue, {} in the constraint)",
+                file.display(),
+                line,
+                name,
+                position,
+                glue_residues,
+                constraint_residues
+            ),
+            GcmError::WeightCountMismatch { weights, glues } => write!(
+                f,
+                "{} weights are given for {} glue alignments",
+                weights, glues
+            ),
+            GcmError::InvalidFormat { file, reason } => {
+                write!(f, "{}: {}", file.display(), reason)
+            }
+            GcmError::InvalidTrace(report) => write!(f, "{}", report),
+            GcmError::Io { file, .. } => write!(f, "I/O error on {}", file.display()),
+        }
+    }
+}
+
+impl std::error::Error for GcmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GcmError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -1,17 +1,21 @@
 use ahash::AHashMap;
+use anyhow::bail;
 use fixedbitset::FixedBitSet;
 use ndarray::{Array, ShapeBuilder};
 
 use crate::{
     cluster::{ClusteringResult, Graph},
+    error::GcmError,
     state::AlnState,
+    verify::VerifyReport,
 };
 
 /// a DP algorithm for solving the exact two row MWT-AM problem.
 /// Not used currently because the Smith-Waterman algorithm seems simpler and faster.
+/// Fails if the resulting trace is invalid.
 pub fn solve_twocase_mwt(
     graph: &AHashMap<(u32, u32), AHashMap<(u32, u32), f64>>,
-) -> ClusteringResult {
+) -> Result<ClusteringResult, GcmError> {
     let mut edges: Vec<((u32, u32), (u32, u32), f64)> = vec![];
     for (u, map) in graph {
         for (v, f) in map {
@@ -47,28 +51,50 @@ pub fn solve_twocase_mwt(
     }
     // println!("{:?}", back);
     let cr = ClusteringResult { clusters: trace };
-    cr.check_validity();
-    cr
-}
-
-/// helper to retrive weight from a graph
-pub fn weight_from_graph(graph: &Graph, i: usize, j: usize) -> f64 {
-    let h1 = graph.sims.get(&i);
-    if let Some(h2) = h1 {
-        if let Some(f) = h2.get(&j) {
-            return *f;
-        }
+    // the column counts are unknown here, so only the order of the clusters is checked
+    let violations = cr.violations(&[usize::MAX; 2]);
+    if !violations.is_empty() {
+        return Err(GcmError::InvalidTrace(VerifyReport { violations }));
     }
-    0.0
+    Ok(cr)
 }
 
 /// Smith-Waterman, but modified to solve the MWT-AM problem
 pub fn sw_algorithm(graph: &Graph, state: &AlnState) -> ClusteringResult {
     let n = state.column_counts[0];
     let m = state.column_counts[1];
+    let matches = sw_matches(n, m, |i, row| {
+        for (v, w) in graph.neighbors(i) {
+            if v >= n {
+                row[v - n] = w;
+            }
+        }
+    });
+    ClusteringResult {
+        clusters: matches
+            .into_iter()
+            .map(|(i, j)| vec![(0, i as u32), (1, j as u32)])
+            .collect(),
+    }
+}
+
+/// The Smith-Waterman-like DP behind [`sw_algorithm`], between two sequences of `n` and `m` columns.
+/// `fill_row(i, row)` adds the weights from the i-th column of the first sequence to the columns
+/// of the second one into the zeroed `row`. Returns the matched pairs of columns in increasing order.
+pub fn sw_matches(
+    n: usize,
+    m: usize,
+    mut fill_row: impl FnMut(usize, &mut [f64]),
+) -> Vec<(usize, usize)> {
     let mut s = Array::<f64, _>::zeros((n + 1, m + 1).f());
     let mut back = Array::<u8, _>::zeros((n + 1, m + 1).f());
+    // weights from the (i-1)-th column of the first sequence to the columns of the second one
+    let mut row = vec![0.0; m];
     for i in 0..(n + 1) {
+        if i > 0 {
+            row.iter_mut().for_each(|w| *w = 0.0);
+            fill_row(i - 1, &mut row);
+        }
         for j in 0..(m + 1) {
             if i == 0 || j == 0 {
                 s[[i, j]] = 0.0;
@@ -76,7 +102,7 @@ pub fn sw_algorithm(graph: &Graph, state: &AlnState) -> ClusteringResult {
             }
             let mut max = 0.0;
             let mut max_pt = 0u8;
-            let w = weight_from_graph(graph, i - 1, n + j - 1);
+            let w = row[j - 1];
             let values = [s[[i - 1, j - 1]] + w, s[[i - 1, j]], s[[i, j - 1]]];
             for (i, &v) in values.iter().enumerate() {
                 if i == 0 && w <= 0.0 {
@@ -92,15 +118,14 @@ pub fn sw_algorithm(graph: &Graph, state: &AlnState) -> ClusteringResult {
             back[[i, j]] = max_pt;
         }
     }
-    let _score = s[[n, m]];
-    let mut matches: Vec<Vec<(u32, u32)>> = Vec::new();
+    let mut matches: Vec<(usize, usize)> = Vec::new();
     let (mut i, mut j) = (n, m);
     while i > 0 && j > 0 {
         let pt = back[[i, j]];
         if pt == 0 {
             i -= 1;
             j -= 1;
-            matches.push(vec![(0, i as u32), (1, j as u32)]);
+            matches.push((i, j));
         } else if pt == 7 {
             // TODO: this branch is not currently used. To be removed.
             i -= 1;
@@ -112,7 +137,7 @@ pub fn sw_algorithm(graph: &Graph, state: &AlnState) -> ClusteringResult {
         }
     }
     matches.reverse();
-    ClusteringResult { clusters: matches }
+    matches
 }
 
 fn can_take(boundary: (u32, u32), edge_x: (u32, u32)) -> bool {
@@ -160,3 +185,184 @@ fn two_case_mwt(
     mem.insert(taken.clone(), maximum);
     maximum
 }
+
+/// Largest number of frontier states explored by [`exact_mwt`]
+pub const EXACT_MAX_STATES: usize = 20_000_000;
+/// Largest number of constraints handled by [`exact_mwt`]
+pub const EXACT_MAX_CONSTRAINTS: usize = 12;
+/// Largest work (frontier states times subsets of constraints) of [`exact_mwt`]
+pub const EXACT_MAX_WORK: usize = 2_000_000_000;
+
+/// Number of frontier states explored by [`exact_mwt`] for constraints of the given lengths,
+/// `None` on overflow.
+pub fn exact_states(column_counts: &[usize]) -> Option<usize> {
+    column_counts
+        .iter()
+        .try_fold(1usize, |acc, &l| acc.checked_mul(l + 1))
+}
+
+/// Work of [`exact_mwt`] for constraints of the given lengths, i.e., its frontier states times
+/// the subsets of constraints considered from each of them, `None` on overflow.
+pub fn exact_work(column_counts: &[usize]) -> Option<usize> {
+    let subsets = 1usize.checked_shl(column_counts.len() as u32)?;
+    exact_states(column_counts)?.checked_mul(subsets)
+}
+
+/// Solves MWT-AM exactly for any number of constraints by a DP over the k-dimensional
+/// frontiers (the number of columns already traced in each constraint). From each frontier,
+/// the next cluster takes the frontier column of a subset of the constraints; only subsets
+/// connected by positive edges are considered, as splitting a disconnected cluster into
+/// consecutive clusters keeps its weight. Fails if the instance is too large, i.e., has more
+/// than [`EXACT_MAX_CONSTRAINTS`] constraints, [`EXACT_MAX_STATES`] frontiers or
+/// [`EXACT_MAX_WORK`] work.
+pub fn exact_mwt(graph: &Graph, state: &AlnState) -> anyhow::Result<ClusteringResult> {
+    let counts = &state.column_counts;
+    let k = counts.len();
+    if k > EXACT_MAX_CONSTRAINTS {
+        bail!(
+            "{} constraints are too many for the exact tracer (at most {}); use another tracer",
+            k,
+            EXACT_MAX_CONSTRAINTS
+        );
+    }
+    let num_states = match exact_states(counts) {
+        Some(n) if n <= EXACT_MAX_STATES => n,
+        _ => bail!(
+            "the exact tracer would explore more than {} frontier states; use another tracer",
+            EXACT_MAX_STATES
+        ),
+    };
+    match exact_work(counts) {
+        Some(work) if work <= EXACT_MAX_WORK => {}
+        _ => bail!(
+            "the exact tracer would take more than {} steps (frontier states times subsets of \
+             constraints); use another tracer",
+            EXACT_MAX_WORK
+        ),
+    }
+    // mixed-radix numbering of the frontiers, the first constraint varying fastest
+    let mut strides = vec![1usize; k];
+    for c in 1..k {
+        strides[c] = strides[c - 1] * (counts[c - 1] + 1);
+    }
+    let offsets = state.column_offsets();
+    let full = 1usize << k;
+    let mut best = vec![0.0f64; num_states];
+    let mut choice = vec![0u32; num_states];
+    // per frontier: weights between the frontier columns, their positive adjacency,
+    // and the weight and connectivity of every subset of them
+    let mut w = vec![0.0f64; k * k];
+    let mut adj = vec![0usize; k];
+    let mut gain = vec![0.0f64; full];
+    let mut connected = vec![false; full];
+    let mut masks = vec![];
+    let mut frontier = counts.clone();
+    for idx in (0..num_states - 1).rev() {
+        // decrement the frontier (odometer-like)
+        for c in 0..k {
+            if frontier[c] > 0 {
+                frontier[c] -= 1;
+                break;
+            }
+            frontier[c] = counts[c];
+        }
+        let mut active = 0usize;
+        for c in 0..k {
+            if frontier[c] < counts[c] {
+                active |= 1 << c;
+            }
+            adj[c] = 0;
+        }
+        for c in 0..k {
+            if active & (1 << c) == 0 {
+                continue;
+            }
+            let u = offsets[c] + frontier[c];
+            for d in (c + 1)..k {
+                if active & (1 << d) == 0 {
+                    continue;
+                }
+                let x = graph.weight(u, offsets[d] + frontier[d]);
+                w[c * k + d] = x;
+                if x > 0.0 {
+                    adj[c] |= 1 << d;
+                    adj[d] |= 1 << c;
+                }
+            }
+        }
+        let mut best_value = f64::NEG_INFINITY;
+        let mut best_mask = 0usize;
+        // enumerate the non-empty subsets of the active constraints in increasing order,
+        // so that the subsets of a mask are processed before it
+        let mut mask = active;
+        masks.clear();
+        while mask > 0 {
+            masks.push(mask);
+            mask = (mask - 1) & active;
+        }
+        for &mask in masks.iter().rev() {
+            let low = mask.trailing_zeros() as usize;
+            let rest = mask & (mask - 1);
+            if rest == 0 {
+                gain[mask] = 0.0;
+                connected[mask] = true;
+            } else {
+                let mut g = gain[rest];
+                let mut bits = rest;
+                while bits > 0 {
+                    let d = bits.trailing_zeros() as usize;
+                    g += w[low * k + d];
+                    bits &= bits - 1;
+                }
+                gain[mask] = g;
+                // a connected set has a vertex whose removal keeps it connected
+                let mut bits = mask;
+                connected[mask] = false;
+                while bits > 0 {
+                    let c = bits.trailing_zeros() as usize;
+                    let without = mask & !(1 << c);
+                    if connected[without] && adj[c] & without != 0 {
+                        connected[mask] = true;
+                        break;
+                    }
+                    bits &= bits - 1;
+                }
+            }
+            if !connected[mask] {
+                continue;
+            }
+            let mut next = idx;
+            let mut bits = mask;
+            while bits > 0 {
+                next += strides[bits.trailing_zeros() as usize];
+                bits &= bits - 1;
+            }
+            let value = gain[mask] + best[next];
+            if value > best_value {
+                best_value = value;
+                best_mask = mask;
+            }
+        }
+        best[idx] = best_value;
+        choice[idx] = best_mask as u32;
+    }
+
+    let mut clusters: Vec<Vec<(u32, u32)>> = vec![];
+    let mut frontier = vec![0usize; k];
+    let mut idx = 0;
+    while idx < num_states - 1 {
+        let mask = choice[idx] as usize;
+        let cluster = (0..k)
+            .filter(|c| mask & (1 << c) != 0)
+            .map(|c| (c as u32, frontier[c] as u32))
+            .collect::<Vec<_>>();
+        for &(c, _) in &cluster {
+            frontier[c as usize] += 1;
+            idx += strides[c as usize];
+        }
+        if cluster.len() > 1 {
+            clusters.push(cluster);
+        }
+    }
+    Ok(ClusteringResult { clusters })
+}
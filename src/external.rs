@@ -1,25 +1,156 @@
 use std::fs::File;
-use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::process::Stdio;
+use std::sync::Arc;
 use std::{fs::rename, path::PathBuf, process::Command};
 
-pub fn request_alignment(in_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+use clap::ArgEnum;
+
+/// An external program turning unaligned sequences into an alignment.
+pub trait Aligner: Send + Sync {
+    /// Aligns the sequences in `in_path`, writing the alignment to `out_path`.
+    fn align(&self, in_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()>;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug, Hash)]
+pub enum AlignerKind {
+    Mafft,
+    Clustalo,
+    Muscle,
+    /// A user-supplied command template
+    Command,
+}
+
+/// Builds the aligner of the given kind, each of its jobs using `job_threads` threads where the
+/// aligner supports it. `template` is only used (and required) by `AlignerKind::Command`.
+pub fn build_aligner(
+    kind: AlignerKind,
+    template: Option<&str>,
+    job_threads: usize,
+) -> anyhow::Result<Arc<dyn Aligner>> {
+    Ok(match kind {
+        AlignerKind::Mafft => Arc::new(Mafft {
+            threads: job_threads.max(1),
+        }),
+        AlignerKind::Clustalo => Arc::new(ClustalOmega),
+        AlignerKind::Muscle => Arc::new(Muscle),
+        AlignerKind::Command => {
+            let template = template
+                .ok_or_else(|| anyhow::anyhow!("the command aligner requires a template"))?;
+            Arc::new(CommandTemplate::new(template)?)
+        }
+    })
+}
+
+fn temp_path(out_path: &PathBuf) -> PathBuf {
     let mut temp_out = out_path.clone();
     temp_out.set_extension("temp");
-    let fd = File::create(&temp_out)?.into_raw_fd();
-    let mut child = Command::new("mafft")
-        .arg("--localpair")
-        .arg("--maxiterate")
-        .arg("1000")
-        .arg("--ep")
-        .arg("0.123")
-        .arg("--quiet")
-        .arg("--thread")
-        .arg("2")
-        .arg(in_path)
-        .stdout(unsafe { Stdio::from_raw_fd(fd) })
-        .spawn()?;
-    child.wait()?;
-    rename(&temp_out, &out_path)?;
+    temp_out
+}
+
+/// Runs `cmd`, failing if it exits unsuccessfully, and moves `temp_out` to `out_path` afterwards.
+fn run_into(mut cmd: Command, temp_out: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+    let status = cmd.status()?;
+    if !status.success() {
+        anyhow::bail!("{:?} failed ({})", cmd, status);
+    }
+    rename(temp_out, out_path)?;
     Ok(())
 }
+
+/// Like `run_into`, but captures the standard output of `cmd` as the alignment.
+fn run_capturing(mut cmd: Command, out_path: &PathBuf) -> anyhow::Result<()> {
+    let temp_out = temp_path(out_path);
+    cmd.stdout(Stdio::from(File::create(&temp_out)?));
+    run_into(cmd, &temp_out, out_path)
+}
+
+pub struct Mafft {
+    /// Threads of each MAFFT job
+    pub threads: usize,
+}
+
+impl Aligner for Mafft {
+    fn align(&self, in_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+        let mut cmd = Command::new("mafft");
+        cmd.arg("--localpair")
+            .arg("--maxiterate")
+            .arg("1000")
+            .arg("--ep")
+            .arg("0.123")
+            .arg("--quiet")
+            .arg("--thread")
+            .arg(self.threads.to_string())
+            .arg(in_path);
+        run_capturing(cmd, out_path)
+    }
+}
+
+pub struct ClustalOmega;
+
+impl Aligner for ClustalOmega {
+    fn align(&self, in_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+        let mut cmd = Command::new("clustalo");
+        cmd.arg("-i").arg(in_path).arg("--outfmt=fa");
+        run_capturing(cmd, out_path)
+    }
+}
+
+/// MUSCLE (v5 command line)
+pub struct Muscle;
+
+impl Aligner for Muscle {
+    fn align(&self, in_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+        let temp_out = temp_path(out_path);
+        let mut cmd = Command::new("muscle");
+        cmd.arg("-align")
+            .arg(in_path)
+            .arg("-output")
+            .arg(&temp_out)
+            .stdout(Stdio::null());
+        run_into(cmd, &temp_out, out_path)
+    }
+}
+
+/// A generic aligner given as a whitespace-separated command template, e.g.
+/// `famsa {input} {output}`. `{input}` is substituted by the unaligned sequences;
+/// `{output}` by the alignment path, and if absent, the standard output is taken as the alignment.
+pub struct CommandTemplate {
+    args: Vec<String>,
+    writes_output: bool,
+}
+
+impl CommandTemplate {
+    pub fn new(template: &str) -> anyhow::Result<Self> {
+        let args: Vec<String> = template.split_whitespace().map(String::from).collect();
+        if args.is_empty() {
+            anyhow::bail!("empty aligner command template");
+        }
+        if !args.iter().any(|a| a.contains("{input}")) {
+            anyhow::bail!("aligner command template {:?} lacks {{input}}", template);
+        }
+        let writes_output = args.iter().any(|a| a.contains("{output}"));
+        Ok(Self {
+            args,
+            writes_output,
+        })
+    }
+}
+
+impl Aligner for CommandTemplate {
+    fn align(&self, in_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+        let temp_out = temp_path(out_path);
+        let input = in_path.to_string_lossy();
+        let output = temp_out.to_string_lossy();
+        let mut args = self
+            .args
+            .iter()
+            .map(|a| a.replace("{input}", &input).replace("{output}", &output));
+        let mut cmd = Command::new(args.next().unwrap());
+        cmd.args(args);
+        if self.writes_output {
+            run_into(cmd, &temp_out, out_path)
+        } else {
+            run_capturing(cmd, out_path)
+        }
+    }
+}
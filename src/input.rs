@@ -0,0 +1,285 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Cursor, Read},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use ahash::AHashMap;
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use lazy_static::lazy_static;
+use seq_io::fasta::Reader;
+
+use crate::error::GcmError;
+
+lazy_static! {
+    /// The standard input, spooled in memory on first use as it may be read several times
+    static ref STDIN: Mutex<Option<Arc<Vec<u8>>>> = Mutex::new(None);
+}
+
+/// Whether the path stands for the standard input (or output), i.e., is `-`.
+pub fn is_stdio(path: &PathBuf) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn stdin_bytes() -> std::io::Result<Arc<Vec<u8>>> {
+    let mut cached = STDIN.lock().unwrap();
+    if cached.is_none() {
+        let mut buf = vec![];
+        std::io::stdin().lock().read_to_end(&mut buf)?;
+        *cached = Some(Arc::new(buf));
+    }
+    Ok(cached.as_ref().unwrap().clone())
+}
+
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Records of any supported input, read as FASTA.
+pub type FastaReader = Reader<Box<dyn Read>>;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum InputFormat {
+    Fasta,
+    /// FASTA with '.' as gaps in insertion columns
+    A2m,
+    Stockholm,
+    Phylip,
+}
+
+fn detect_compression(magic: &[u8]) -> Compression {
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Compression::Zstd
+    } else if magic.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else {
+        Compression::None
+    }
+}
+
+/// Detects the format from the first bytes of the (decompressed) input and the file name.
+fn detect_format(head: &[u8], path: &PathBuf) -> InputFormat {
+    let start = head
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(head.len());
+    let head = &head[start..];
+    if head.starts_with(b"# STOCKHOLM") {
+        return InputFormat::Stockholm;
+    }
+    if head.first().map_or(false, |c| c.is_ascii_digit()) {
+        return InputFormat::Phylip;
+    }
+    let name = path.to_string_lossy().to_ascii_lowercase();
+    let gapped_by_dots = head
+        .split(|&c| c == b'\n')
+        .filter(|l| !l.starts_with(b">"))
+        .any(|l| l.contains(&b'.'));
+    if name.contains(".a2m") || gapped_by_dots {
+        InputFormat::A2m
+    } else {
+        InputFormat::Fasta
+    }
+}
+
+/// Opens sequences or an alignment for reading as FASTA records, `-` standing for the standard input.
+/// Gzip, zstd and bzip2 compressed inputs are decompressed transparently, and A2M, Stockholm
+/// and PHYLIP inputs are converted to FASTA.
+pub fn open_fasta(path: &PathBuf) -> Result<FastaReader, GcmError> {
+    let io_err = |source: std::io::Error| GcmError::Io {
+        file: path.clone(),
+        source,
+    };
+    let stdin = is_stdio(path);
+    let source: Box<dyn Read> = if stdin {
+        Box::new(Cursor::new(SharedBytes(stdin_bytes().map_err(io_err)?)))
+    } else {
+        Box::new(File::open(path).map_err(io_err)?)
+    };
+    let mut raw = BufReader::new(source);
+    let compression = detect_compression(raw.fill_buf().map_err(io_err)?);
+    let decompressed: Box<dyn Read> = match compression {
+        Compression::None => Box::new(raw),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(raw)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(raw).map_err(io_err)?),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(raw)),
+    };
+    let mut text = BufReader::new(decompressed);
+    let format = detect_format(text.fill_buf().map_err(io_err)?, path);
+    let reader: Box<dyn Read> = match format {
+        InputFormat::Fasta => Box::new(text),
+        InputFormat::A2m => Box::new(DotsAsGaps {
+            inner: text,
+            line_start: true,
+            in_header: false,
+        }),
+        InputFormat::Stockholm | InputFormat::Phylip => {
+            let mut content = vec![];
+            text.read_to_end(&mut content).map_err(io_err)?;
+            let parsed = match format {
+                InputFormat::Stockholm => parse_stockholm(&content),
+                _ => parse_phylip(&content),
+            };
+            let records = parsed.map_err(|reason| GcmError::InvalidFormat {
+                file: path.clone(),
+                reason,
+            })?;
+            Box::new(Cursor::new(to_fasta(&records)))
+        }
+    };
+    Ok(Reader::new(reader))
+}
+
+/// Replaces '.' by '-' outside of the header lines.
+struct DotsAsGaps<R: Read> {
+    inner: R,
+    line_start: bool,
+    in_header: bool,
+}
+
+impl<R: Read> Read for DotsAsGaps<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for c in &mut buf[..n] {
+            if self.line_start && *c == b'>' {
+                self.in_header = true;
+            } else if *c == b'.' && !self.in_header {
+                *c = b'-';
+            }
+            self.line_start = *c == b'\n';
+            if self.line_start {
+                self.in_header = false;
+            }
+        }
+        Ok(n)
+    }
+}
+
+type Records = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn to_fasta(records: &Records) -> Vec<u8> {
+    let mut out = vec![];
+    for (name, seq) in records {
+        out.push(b'>');
+        out.extend_from_slice(name);
+        out.push(b'\n');
+        out.extend_from_slice(seq);
+        out.push(b'\n');
+    }
+    out
+}
+
+fn tokens(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    line.split(|c| c.is_ascii_whitespace())
+        .filter(|t| !t.is_empty())
+}
+
+fn non_empty_lines(content: &[u8]) -> impl Iterator<Item = &[u8]> {
+    content
+        .split(|&c| c == b'\n')
+        .filter(|l| tokens(l).next().is_some())
+}
+
+/// Parses (possibly interleaved) Stockholm, keeping the order in which the names first appear.
+fn parse_stockholm(content: &[u8]) -> Result<Records, String> {
+    let mut records: Records = vec![];
+    let mut index: AHashMap<Vec<u8>, usize> = AHashMap::default();
+    for line in non_empty_lines(content) {
+        if line.starts_with(b"//") {
+            break;
+        }
+        if line.starts_with(b"#") {
+            continue;
+        }
+        let mut parts = tokens(line);
+        let name = parts.next().unwrap().to_vec();
+        let seq = parts.flatten().map(|&c| if c == b'.' { b'-' } else { c });
+        let i = *index.entry(name.clone()).or_insert_with(|| {
+            records.push((name, vec![]));
+            records.len() - 1
+        });
+        records[i].1.extend(seq);
+    }
+    if records.is_empty() {
+        return Err("no sequences found in Stockholm alignment".to_string());
+    }
+    Ok(records)
+}
+
+/// Parses relaxed PHYLIP, either sequential (possibly spanning several lines per sequence)
+/// or interleaved.
+fn parse_phylip(content: &[u8]) -> Result<Records, String> {
+    let mut lines = non_empty_lines(content);
+    let header = lines.next().ok_or("empty PHYLIP file")?;
+    let dims: Vec<usize> = tokens(header)
+        .take(2)
+        .map(|t| String::from_utf8_lossy(t).parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| "malformed PHYLIP header")?;
+    if dims.len() < 2 {
+        return Err("malformed PHYLIP header".to_string());
+    }
+    let (ntax, nchar) = (dims[0], dims[1]);
+    let lines: Vec<&[u8]> = lines.collect();
+    let named = |line: &[u8]| -> (Vec<u8>, Vec<u8>) {
+        let mut parts = tokens(line);
+        let name = parts.next().unwrap().to_vec();
+        (name, parts.flatten().copied().collect())
+    };
+    let complete =
+        |records: &Records| records.len() == ntax && records.iter().all(|(_, s)| s.len() == nchar);
+
+    // sequential
+    let mut records: Records = vec![];
+    let mut rest = lines.iter();
+    while records.len() < ntax {
+        let line = match rest.next() {
+            Some(&l) => l,
+            None => break,
+        };
+        let mut record = named(line);
+        while record.1.len() < nchar {
+            match rest.next() {
+                Some(l) => record.1.extend(tokens(l).flatten()),
+                None => break,
+            }
+        }
+        records.push(record);
+    }
+    if complete(&records) && rest.next().is_none() {
+        return Ok(records);
+    }
+
+    // interleaved
+    if lines.len() < ntax {
+        return Err(format!("expected {} sequences in PHYLIP file", ntax));
+    }
+    let mut records: Records = lines[..ntax].iter().map(|&l| named(l)).collect();
+    for (i, line) in lines[ntax..].iter().enumerate() {
+        records[i % ntax].1.extend(tokens(line).flatten());
+    }
+    if complete(&records) {
+        Ok(records)
+    } else {
+        Err(format!(
+            "could not read {} sequences of length {} from PHYLIP file",
+            ntax, nchar
+        ))
+    }
+}
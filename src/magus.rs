@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+
+use ahash::AHashMap;
+use petgraph::{
+    algo::{tarjan_scc, toposort},
+    graph::DiGraph,
+};
+
+use crate::{
+    cluster::{ClusteringResult, Graph},
+    state::AlnState,
+};
+
+/// Gains smaller than this are ignored by the FM passes.
+const EPSILON: f64 = 1e-9;
+/// Largest number of improving passes of each FM bipartition
+const FM_MAX_PASSES: usize = 8;
+
+/// A greedy approximation of MAGUS's MinClusters, turning unordered (possibly conflicting)
+/// clusters into a valid trace: walking the constraints left to right, the columns at the frontier
+/// are grouped by cluster, and the group covering the largest fraction of the remaining columns of
+/// its cluster (then the largest group) is emitted, so that clusters are broken as little as
+/// possible. Unlike MAGUS, which searches over frontier states with a bounded heap, this makes a
+/// single greedy pass, so the traces of the two may differ.
+pub fn min_clusters(
+    graph: &Graph,
+    state: &AlnState,
+    clusters: &ClusteringResult,
+) -> ClusteringResult {
+    Frontier::new(graph, state, clusters).run(|frontier, groups| frontier.least_breaking(groups))
+}
+
+/// Region growing, as in MAGUS: as (greedy) [`min_clusters`], but the emitted group is the heaviest
+/// region grown within the frontier columns of a cluster, from the column most connected to the
+/// others, by repeatedly adding the column most connected to the region. Falls back to the
+/// MinClusters choice when no frontier columns of the same cluster share an edge.
+pub fn region_growing(
+    graph: &Graph,
+    state: &AlnState,
+    clusters: &ClusteringResult,
+) -> ClusteringResult {
+    Frontier::new(graph, state, clusters).run(|frontier, groups| {
+        let best = groups
+            .iter()
+            .filter(|group| group.len() > 1)
+            .map(|group| frontier.grow_region(group))
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        match best {
+            Some((weight, region)) if weight > 0.0 => region,
+            _ => frontier.least_breaking(groups),
+        }
+    })
+}
+
+/// Fiduccia-Mattheyses, as in MAGUS: while a cluster holds two columns of the same constraint,
+/// or clusters are in a cycle of the order they induce on the constraints (in which case the
+/// largest one of the cycle is taken), the cluster is split in two by FM min-cut bipartitioning,
+/// with two conflicting columns fixed on either side. The clusters are then ordered topologically.
+pub fn fm(graph: &Graph, state: &AlnState, clusters: &ClusteringResult) -> ClusteringResult {
+    let offsets = state.column_offsets();
+    let mut clusters: Vec<Vec<usize>> = clusters
+        .clusters
+        .iter()
+        .map(|cluster| {
+            cluster
+                .iter()
+                .map(|&(c, i)| offsets[c as usize] + i as usize)
+                .collect()
+        })
+        .collect();
+    loop {
+        match cluster_order(graph, state, &offsets, &clusters) {
+            Ok(order) => {
+                let clusters = order
+                    .into_iter()
+                    .map(|x| std::mem::take(&mut clusters[x]))
+                    .filter(|cluster| cluster.len() > 1)
+                    .map(|mut cluster| {
+                        cluster.sort_unstable();
+                        cluster.into_iter().map(|u| graph.node_pos[u]).collect()
+                    })
+                    .collect();
+                return ClusteringResult { clusters };
+            }
+            Err(conflicts) => {
+                for (x, a, b) in conflicts {
+                    let (lhs, rhs) = bipartition(graph, &clusters[x], a, b);
+                    clusters[x] = lhs;
+                    clusters.push(rhs);
+                }
+            }
+        }
+    }
+}
+
+/// The order induced by the clusters on the constraints, as a topological order of the clusters
+/// if there are no conflicts, and otherwise the clusters to split along with two columns
+/// to separate.
+fn cluster_order(
+    graph: &Graph,
+    state: &AlnState,
+    offsets: &[usize],
+    clusters: &[Vec<usize>],
+) -> Result<Vec<usize>, Vec<(usize, usize, usize)>> {
+    let mut cluster_of: Vec<Option<usize>> = vec![None; graph.size];
+    let mut conflicts = vec![];
+    for (x, cluster) in clusters.iter().enumerate() {
+        let mut duplicate = None;
+        let mut seen: AHashMap<u32, usize> = AHashMap::default();
+        for &u in cluster {
+            cluster_of[u] = Some(x);
+            if let Some(&v) = seen.get(&graph.node_pos[u].0) {
+                duplicate.get_or_insert((v, u));
+            }
+            seen.insert(graph.node_pos[u].0, u);
+        }
+        if let Some((a, b)) = duplicate {
+            conflicts.push((x, a, b));
+        }
+    }
+    let mut order: DiGraph<(), ()> = DiGraph::with_capacity(clusters.len(), graph.size);
+    let nodes = (0..clusters.len())
+        .map(|_| order.add_node(()))
+        .collect::<Vec<_>>();
+    for (c, &count) in state.column_counts.iter().enumerate() {
+        let mut prev: Option<usize> = None;
+        for i in 0..count {
+            if let Some(x) = cluster_of[offsets[c] + i] {
+                if let Some(p) = prev.filter(|&p| p != x) {
+                    order.add_edge(nodes[p], nodes[x], ());
+                }
+                prev = Some(x);
+            }
+        }
+    }
+    let mut splitting = vec![false; clusters.len()];
+    for &(x, _, _) in &conflicts {
+        splitting[x] = true;
+    }
+    for component in tarjan_scc(&order) {
+        if component.len() < 2 || component.iter().any(|n| splitting[n.index()]) {
+            continue;
+        }
+        let x = component
+            .iter()
+            .map(|n| n.index())
+            .max_by_key(|&x| clusters[x].len())
+            .unwrap();
+        // separate the relatively earliest and latest columns of the cluster
+        let relative = |u: usize| {
+            let (c, i) = graph.node_pos[u];
+            (i as f64 + 0.5) / state.column_counts[c as usize] as f64
+        };
+        let mut members = clusters[x].clone();
+        members.sort_by(|&u, &v| relative(u).partial_cmp(&relative(v)).unwrap());
+        splitting[x] = true;
+        conflicts.push((x, members[0], members[members.len() - 1]));
+    }
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+    let sorted = toposort(&order, None).expect("clusters without conflicts are acyclic");
+    Ok(sorted.into_iter().map(|n| n.index()).collect())
+}
+
+/// Splits `members` in two sides, `a` and `b` being fixed on either side, by Fiduccia-Mattheyses
+/// passes minimizing the weight of the edges between the sides.
+fn bipartition(graph: &Graph, members: &[usize], a: usize, b: usize) -> (Vec<usize>, Vec<usize>) {
+    let local: AHashMap<usize, usize> = members.iter().enumerate().map(|(i, &u)| (u, i)).collect();
+    let adj: Vec<Vec<(usize, f64)>> = members
+        .iter()
+        .map(|&u| {
+            graph
+                .neighbors(u)
+                .filter_map(|(v, w)| local.get(&v).map(|&j| (j, w)))
+                .collect()
+        })
+        .collect();
+    let (a, b) = (local[&a], local[&b]);
+    // false for the side of `a`, true for the side of `b`, starting with the closer seed
+    let to = |i: usize, seed: usize| -> f64 {
+        adj[i]
+            .iter()
+            .filter(|&&(j, _)| j == seed)
+            .map(|&(_, w)| w)
+            .sum()
+    };
+    let mut side: Vec<bool> = (0..members.len()).map(|i| to(i, b) > to(i, a)).collect();
+    side[a] = false;
+    side[b] = true;
+    for _ in 0..FM_MAX_PASSES {
+        // weight gained in the cut by moving each member to the other side
+        let mut gain: Vec<f64> = (0..members.len())
+            .map(|i| {
+                adj[i]
+                    .iter()
+                    .map(|&(j, w)| if side[i] == side[j] { -w } else { w })
+                    .sum()
+            })
+            .collect();
+        let mut locked = vec![false; members.len()];
+        locked[a] = true;
+        locked[b] = true;
+        let mut moves = vec![];
+        let (mut total, mut best, mut best_len) = (0.0, 0.0, 0);
+        while let Some(i) = (0..members.len())
+            .filter(|&i| !locked[i])
+            .max_by(|&i, &j| gain[i].partial_cmp(&gain[j]).unwrap())
+        {
+            side[i] = !side[i];
+            locked[i] = true;
+            total += gain[i];
+            moves.push(i);
+            for &(j, w) in &adj[i] {
+                gain[j] += if side[i] == side[j] {
+                    -2.0 * w
+                } else {
+                    2.0 * w
+                };
+            }
+            gain[i] = -gain[i];
+            if total > best + EPSILON {
+                best = total;
+                best_len = moves.len();
+            }
+        }
+        for &i in &moves[best_len..] {
+            side[i] = !side[i];
+        }
+        if best_len == 0 {
+            break;
+        }
+    }
+    let (mut lhs, mut rhs) = (vec![], vec![]);
+    for (i, &u) in members.iter().enumerate() {
+        if side[i] {
+            rhs.push(u);
+        } else {
+            lhs.push(u);
+        }
+    }
+    (lhs, rhs)
+}
+
+/// The columns of the constraints not traced yet, the next ones forming the frontier.
+struct Frontier<'a> {
+    graph: &'a Graph,
+    offsets: Vec<usize>,
+    counts: Vec<usize>,
+    next: Vec<usize>,
+    cluster_of: Vec<Option<usize>>,
+    /// number of columns of each cluster not traced yet
+    remaining: Vec<usize>,
+}
+
+impl<'a> Frontier<'a> {
+    fn new(graph: &'a Graph, state: &AlnState, clusters: &ClusteringResult) -> Self {
+        let offsets = state.column_offsets();
+        let mut cluster_of = vec![None; graph.size];
+        for (x, cluster) in clusters.clusters.iter().enumerate() {
+            for &(c, i) in cluster {
+                cluster_of[offsets[c as usize] + i as usize] = Some(x);
+            }
+        }
+        Self {
+            graph,
+            offsets,
+            counts: state.column_counts.clone(),
+            next: vec![0; state.column_counts.len()],
+            cluster_of,
+            remaining: clusters.clusters.iter().map(|c| c.len()).collect(),
+        }
+    }
+
+    /// The frontier columns grouped by cluster, columns outside of any cluster on their own.
+    fn groups(&self) -> Vec<Vec<usize>> {
+        let mut by_cluster: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut groups = vec![];
+        for c in 0..self.counts.len() {
+            if self.next[c] == self.counts[c] {
+                continue;
+            }
+            let u = self.offsets[c] + self.next[c];
+            match self.cluster_of[u] {
+                Some(x) => by_cluster.entry(x).or_default().push(u),
+                None => groups.push(vec![u]),
+            }
+        }
+        groups.extend(by_cluster.into_values());
+        groups
+    }
+
+    /// Fraction of the remaining columns of its cluster covered by a group.
+    fn completeness(&self, group: &[usize]) -> f64 {
+        match self.cluster_of[group[0]] {
+            Some(x) => group.len() as f64 / self.remaining[x] as f64,
+            None => 1.0,
+        }
+    }
+
+    fn least_breaking(&self, groups: Vec<Vec<usize>>) -> Vec<usize> {
+        groups
+            .into_iter()
+            .map(|group| (self.completeness(&group), group))
+            .max_by(|a, b| {
+                a.0.partial_cmp(&b.0)
+                    .unwrap()
+                    .then(a.1.len().cmp(&b.1.len()))
+            })
+            .unwrap()
+            .1
+    }
+
+    /// The heaviest region grown within a group, along with its weight.
+    fn grow_region(&self, group: &[usize]) -> (f64, Vec<usize>) {
+        let to = |u: usize, others: &[usize]| -> f64 {
+            others.iter().map(|&v| self.graph.weight(u, v)).sum()
+        };
+        let seed = *group
+            .iter()
+            .max_by(|&&u, &&v| to(u, group).partial_cmp(&to(v, group)).unwrap())
+            .unwrap();
+        let mut region = vec![seed];
+        let mut rest: Vec<usize> = group.iter().copied().filter(|&u| u != seed).collect();
+        // weight between each remaining column and the region, updated as columns join it
+        let mut connection: Vec<f64> = rest.iter().map(|&u| self.graph.weight(u, seed)).collect();
+        let mut total = 0.0;
+        while let Some((k, &w)) = connection
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+        {
+            if w <= 0.0 {
+                break;
+            }
+            total += w;
+            connection.swap_remove(k);
+            let joined = rest.swap_remove(k);
+            for (&u, c) in rest.iter().zip(connection.iter_mut()) {
+                *c += self.graph.weight(u, joined);
+            }
+            region.push(joined);
+        }
+        (total, region)
+    }
+
+    fn advance(&mut self, columns: &[usize]) {
+        for &u in columns {
+            self.next[self.graph.node_pos[u].0 as usize] += 1;
+            if let Some(x) = self.cluster_of[u] {
+                self.remaining[x] -= 1;
+            }
+        }
+    }
+
+    /// Traces all columns, `pick` choosing which of the frontier groups (or part of one) goes next.
+    fn run(mut self, pick: impl Fn(&Self, Vec<Vec<usize>>) -> Vec<usize>) -> ClusteringResult {
+        let mut clusters = vec![];
+        loop {
+            let groups = self.groups();
+            if groups.is_empty() {
+                break;
+            }
+            let mut chosen = pick(&self, groups);
+            self.advance(&chosen);
+            if chosen.len() > 1 {
+                chosen.sort_unstable();
+                clusters.push(chosen.into_iter().map(|u| self.graph.node_pos[u]).collect());
+            }
+        }
+        ClusteringResult { clusters }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sprs::TriMat;
+
+    use super::*;
+
+    /// Complete graphs of unit weight over the given clusters, along with the state of
+    /// constraints with `column_counts` columns.
+    fn clustered_graph(column_counts: &[usize], clusters: &ClusteringResult) -> (Graph, AlnState) {
+        let mut state = AlnState::new();
+        state.column_counts = column_counts.to_vec();
+        let offsets = state.column_offsets();
+        let node_pos: Vec<(u32, u32)> = column_counts
+            .iter()
+            .enumerate()
+            .flat_map(|(c, &count)| (0..count).map(move |i| (c as u32, i as u32)))
+            .collect();
+        let n = node_pos.len();
+        let mut upper = TriMat::new((n, n));
+        for cluster in &clusters.clusters {
+            let nodes: Vec<usize> = cluster
+                .iter()
+                .map(|&(c, i)| offsets[c as usize] + i as usize)
+                .collect();
+            for (k, &u) in nodes.iter().enumerate() {
+                for &v in &nodes[k + 1..] {
+                    upper.add_triplet(u.min(v), u.max(v), 1.0);
+                }
+            }
+        }
+        (Graph::from_upper(&upper.to_csr(), node_pos), state)
+    }
+
+    #[test]
+    fn conflicting_clusters_are_resolved_into_valid_traces() {
+        let column_counts = [2, 2, 3];
+        // the clusters cross on constraint 1, and the second one holds two columns of constraint 2
+        let clusters = ClusteringResult {
+            clusters: vec![
+                vec![(0, 0), (1, 1), (2, 0)],
+                vec![(0, 1), (1, 0), (2, 1), (2, 2)],
+            ],
+        };
+        let (graph, state) = clustered_graph(&column_counts, &clusters);
+        assert!(!clusters.violations(&column_counts).is_empty());
+        for res in [
+            min_clusters(&graph, &state, &clusters),
+            fm(&graph, &state, &clusters),
+            region_growing(&graph, &state, &clusters),
+        ] {
+            assert!(!res.clusters.is_empty());
+            assert!(res.violations(&column_counts).is_empty(), "{:?}", res);
+        }
+    }
+}
@@ -1,17 +1,35 @@
+mod accuracy;
 mod aln;
 mod cluster;
 mod combined;
+mod error;
 mod exact_solver;
 mod external;
+mod input;
+mod magus;
+mod mcl;
 mod merge;
 mod naive_upgma;
+mod output;
+mod progressive;
+mod refine;
+mod report;
+mod residues;
 mod state;
 mod utils;
+mod validate;
+mod verify;
 
+use anyhow::Context;
 use clap::{Parser, Subcommand};
-use cluster::GCMStep;
+use cluster::{GCMStep, Linkage};
+use combined::MergeOptions;
+use external::{build_aligner, AlignerKind};
 use ordered_float::NotNan;
-use std::path::PathBuf;
+use output::OutputFormat;
+use refine::RefineBudget;
+use residues::ResidueMode;
+use std::{path::PathBuf, time::Duration};
 use tracing::info;
 
 #[derive(Parser, Debug, Hash, PartialEq)]
@@ -19,27 +37,146 @@ use tracing::info;
 struct Args {
     #[clap(subcommand)]
     cmd: SubCommand,
+    /// Number of threads for graph construction and concurrent aligner jobs [default: all cores]
+    #[clap(long, global = true)]
+    threads: Option<usize>,
+}
+
+/// Tracing, scoring and output arguments shared by the subcommands that merge alignments
+#[derive(Parser, Debug, PartialEq, Hash)]
+struct SharedArgs {
+    /// Tracing strategy
+    #[clap(short, long, arg_enum, default_value_t = GCMStep::Auto)]
+    tracer: GCMStep,
+    /// Linkage of the UPGMA tracer
+    #[clap(long, arg_enum, default_value_t = Linkage::Average)]
+    linkage: Linkage,
+    /// Inflation of the MCL tracer
+    #[clap(long, default_value = "4")]
+    inflation: NotNan<f64>,
+    /// Optional weights to the glues, same length as glue alignments
+    #[clap(short, long, multiple_values = true)]
+    weights: Vec<NotNan<f64>>,
+    /// How glue residues are matched to constraint residues of the same taxon
+    #[clap(long, arg_enum, default_value_t = ResidueMode::Trust)]
+    residues: ResidueMode,
+    /// Read glues one at a time, bounding memory to one glue plus the merged graph
+    #[clap(long)]
+    low_memory: bool,
+    /// Output alignment format
+    #[clap(short = 'f', long, arg_enum, default_value_t = OutputFormat::Fasta)]
+    output_format: OutputFormat,
+    /// Alignment columns per output line, 0 for unwrapped
+    #[clap(long, default_value_t = 60)]
+    line_width: usize,
+    /// Passes of local search improving the trace, 0 for none (or no limit with --refine-seconds)
+    #[clap(long, default_value_t = 0)]
+    refine_iterations: usize,
+    /// Time limit of the local search in seconds
+    #[clap(long)]
+    refine_seconds: Option<NotNan<f64>>,
+    /// Write the achieved alignment score and per-constraint statistics as JSON
+    #[clap(long)]
+    report: Option<PathBuf>,
+    /// Write timings and peak memory per stage with graph and cluster sizes, as TSV if the
+    /// path ends with .tsv and JSON otherwise
+    #[clap(long)]
+    run_report: Option<PathBuf>,
+    /// Check that the merged alignment keeps every column of the subset alignments, failing
+    /// with the list of violations otherwise
+    #[clap(long)]
+    verify: bool,
+    /// Output merged alignment path, `-` for the standard output
+    #[clap(short, long)]
+    output: PathBuf,
+}
+
+impl SharedArgs {
+    /// The glue weights, `None` if none were given
+    fn weights(&self) -> Option<Vec<NotNan<f64>>> {
+        if self.weights.is_empty() {
+            None
+        } else {
+            Some(self.weights.clone())
+        }
+    }
+}
+
+impl From<&SharedArgs> for MergeOptions {
+    fn from(args: &SharedArgs) -> Self {
+        Self {
+            tracer: args.tracer,
+            linkage: args.linkage,
+            inflation: args.inflation.into_inner(),
+            residue_mode: args.residues,
+            streaming: args.low_memory,
+            output_format: args.output_format,
+            line_width: args.line_width,
+            refine: RefineBudget {
+                iterations: args.refine_iterations,
+                time: args
+                    .refine_seconds
+                    .map(|s| Duration::from_secs_f64(s.into_inner())),
+            },
+            report: args.report.clone(),
+            run_report: args.run_report.clone(),
+            verify: args.verify,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug, PartialEq, Hash)]
 enum SubCommand {
     /// Run GCM using existing subset and glue alignments
     Merge {
+        /// Subset alignments, one of which may be `-` for the standard input
+        #[clap(short, long, multiple_values = true)]
+        input: Vec<PathBuf>,
+        /// Glue alignments
+        #[clap(short, long, multiple_values = true)]
+        glues: Vec<PathBuf>,
+        #[clap(flatten)]
+        shared: SharedArgs,
+    },
+
+    /// Check subset and glue alignments for problems without merging them
+    Validate {
         /// Subset alignments
         #[clap(short, long, multiple_values = true)]
         input: Vec<PathBuf>,
         /// Glue alignments
         #[clap(short, long, multiple_values = true)]
         glues: Vec<PathBuf>,
-        /// Tracing strategy
-        #[clap(short, long, arg_enum, default_value_t = GCMStep::Auto)]
-        tracer: GCMStep,
         /// Optional weights to the glues, same length as glue alignments
         #[clap(short, long, multiple_values = true)]
         weights: Vec<NotNan<f64>>,
-        /// Output merged alignment path
+    },
+
+    /// Check that a merged alignment induces every subset alignment, column for column
+    Verify {
+        /// Subset alignments
+        #[clap(short, long, multiple_values = true)]
+        input: Vec<PathBuf>,
+        /// Merged alignment, `-` for the standard input
+        #[clap(short, long)]
+        merged: PathBuf,
+    },
+
+    /// Compare an estimated alignment to a reference, printing SP-score, modeler score, TC,
+    /// SPFN and SPFP
+    Score {
+        /// Reference alignment
+        #[clap(short, long)]
+        reference: PathBuf,
+        /// Estimated alignment
         #[clap(short, long)]
-        output: PathBuf,
+        estimated: PathBuf,
+        /// Compare only the taxa listed in this file, one name per line
+        #[clap(long)]
+        taxa: Option<PathBuf>,
+        /// Also write the scores as JSON
+        #[clap(long)]
+        report: Option<PathBuf>,
     },
 
     /// Slice unaligned sequences into unaligned subsets and glues
@@ -57,6 +194,63 @@ enum SubCommand {
         #[clap(short = 's', long)]
         max_size: Option<usize>,
     },
+
+    /// Merge subset alignments using glues sampled from the subsets themselves
+    Stitch {
+        /// Subset alignments
+        #[clap(short, long, multiple_values = true)]
+        input: Vec<PathBuf>,
+        /// Number and size of glues, in the form of <count>x<size>
+        #[clap(short, long, value_parser = parse_axb, default_value = "10x200")]
+        glues: (usize, usize),
+        /// Working directory for the sampled glues
+        #[clap(short = 'd', long)]
+        outdir: PathBuf,
+        /// Aligner used for the glues
+        #[clap(long, arg_enum, default_value_t = AlignerKind::Mafft)]
+        glue_aligner: AlignerKind,
+        /// Command template for the glue aligner
+        #[clap(long)]
+        glue_template: Option<String>,
+        #[clap(flatten)]
+        shared: SharedArgs,
+    },
+
+    /// Align unaligned sequences end-to-end: slice, align subsets and glues, then merge
+    Align {
+        /// Unaligned sequences, `-` for the standard input
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Guide tree used for slicing the sequences
+        #[clap(long)]
+        tree: PathBuf,
+        /// Number and size of glues, in the form of <count>x<size>
+        #[clap(short, long, value_parser = parse_axb, default_value = "10x200")]
+        glues: (usize, usize),
+        /// Working directory for the intermediate subsets and glues
+        #[clap(short = 'd', long)]
+        outdir: PathBuf,
+        /// Maximum number of subsets
+        #[clap(short = 'c', long)]
+        max_count: Option<usize>,
+        /// Maximum size of each subset
+        #[clap(short = 's', long)]
+        max_size: Option<usize>,
+        /// Aligner used for the subsets
+        #[clap(long, arg_enum, default_value_t = AlignerKind::Mafft)]
+        constraint_aligner: AlignerKind,
+        /// Command template for the subset aligner, e.g. "famsa {input} {output}"
+        #[clap(long)]
+        constraint_template: Option<String>,
+        /// Aligner used for the glues
+        #[clap(long, arg_enum, default_value_t = AlignerKind::Mafft)]
+        glue_aligner: AlignerKind,
+        /// Command template for the glue aligner
+        #[clap(long)]
+        glue_template: Option<String>,
+        #[clap(flatten)]
+        shared: SharedArgs,
+    },
 }
 
 fn parse_axb(s: &str) -> Result<(usize, usize), String> {
@@ -77,27 +271,36 @@ fn parse_axb(s: &str) -> Result<(usize, usize), String> {
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() {
+    // logs go to stderr, keeping stdout free for the output alignment
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    if let Err(e) = run().await {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
     use std::time::Instant;
     let now = Instant::now();
-    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
     rayon::ThreadPoolBuilder::new()
-        .num_threads(1)
+        .num_threads(threads)
         .build_global()?;
-    let args = Args::parse();
+    info!("Using {} threads", threads);
     match args.cmd {
         SubCommand::Merge {
             input,
             glues,
-            tracer,
-            weights,
-            output,
+            shared,
         } => {
-            let w = if weights.is_empty() {
-                None
-            } else {
-                Some(weights)
-            };
+            let w = shared.weights();
             info!("Analysis: merging alignments");
             info!(
                 "Merging configuration (# alignments to merge, # glues, weights): {}, {}, {:?}",
@@ -105,8 +308,49 @@ async fn main() -> anyhow::Result<()> {
                 glues.len(),
                 w
             );
-            combined::oneshot_merge_alignments(&input, &glues, tracer, &w, &output)
-                .expect("Failed to merge alignments");
+            let options = MergeOptions::from(&shared);
+            combined::oneshot_merge_alignments(&input, &glues, &w, &options, &shared.output)
+                .context("Failed to merge alignments")?;
+        }
+        SubCommand::Validate {
+            input,
+            glues,
+            weights,
+        } => {
+            info!("Analysis: validating alignments");
+            let w = if weights.is_empty() {
+                None
+            } else {
+                Some(weights)
+            };
+            let report = validate::validate_inputs(&input, &glues, &w)?;
+            if !report.is_ok() {
+                return Err(report.into());
+            }
+            info!("No problems found in the inputs");
+        }
+        SubCommand::Verify { input, merged } => {
+            info!("Analysis: verifying a merged alignment");
+            let report = verify::verify_merged(&input, &merged)?;
+            if !report.is_ok() {
+                return Err(report.into());
+            }
+            info!("The merged alignment induces every subset alignment");
+        }
+        SubCommand::Score {
+            reference,
+            estimated,
+            taxa,
+            report,
+        } => {
+            info!("Analysis: scoring an alignment against a reference");
+            let taxa = taxa.as_ref().map(accuracy::read_taxa).transpose()?;
+            let score = accuracy::score_alignments(&reference, &estimated, taxa.as_ref())?;
+            score.log();
+            score.print();
+            if let Some(path) = &report {
+                report::write_json(path, &score)?;
+            }
         }
         SubCommand::Slice {
             input,
@@ -119,6 +363,65 @@ async fn main() -> anyhow::Result<()> {
             info!("Analysis: slicing unaligned sequences.");
             combined::oneshot_slice_sequences(&input, &tree, glues, max_count, max_size, &outdir)?;
         }
+        SubCommand::Stitch {
+            input,
+            glues,
+            outdir,
+            glue_aligner,
+            glue_template,
+            shared,
+        } => {
+            info!("Analysis: stitching alignments with sampled glues.");
+            let options = MergeOptions::from(&shared);
+            // up to `threads` aligner jobs run at once, so each of them gets a single thread
+            let glue_aligner = build_aligner(glue_aligner, glue_template.as_deref(), 1)?;
+            combined::oneshot_stitch_alignments(
+                &input,
+                glues,
+                &shared.weights(),
+                &options,
+                glue_aligner,
+                threads,
+                &outdir,
+                &shared.output,
+            )
+            .await?;
+        }
+        SubCommand::Align {
+            input,
+            tree,
+            glues,
+            outdir,
+            max_count,
+            max_size,
+            constraint_aligner,
+            constraint_template,
+            glue_aligner,
+            glue_template,
+            shared,
+        } => {
+            info!("Analysis: aligning unaligned sequences end-to-end.");
+            let options = MergeOptions::from(&shared);
+            // up to `threads` aligner jobs run at once, so each of them gets a single thread
+            let constraint_aligner =
+                build_aligner(constraint_aligner, constraint_template.as_deref(), 1)?;
+            let glue_aligner = build_aligner(glue_aligner, glue_template.as_deref(), 1)?;
+            combined::oneshot_align_sequences(
+                &input,
+                &tree,
+                glues,
+                &shared.weights(),
+                max_count,
+                max_size,
+                &options,
+                constraint_aligner,
+                glue_aligner,
+                threads,
+                &outdir,
+                &shared.output,
+            )
+            .await?;
+        }
     }
     info!("Total elapsed time: {:?}", now.elapsed());
     Ok(())
@@ -0,0 +1,124 @@
+use ahash::AHashMap;
+use petgraph::unionfind::UnionFind;
+use sprs::{CsMat, TriMat};
+use tracing::debug;
+
+use crate::cluster::{ClusteringResult, Graph};
+
+const MCL_MAX_ITERATIONS: usize = 100;
+/// Entries below this fraction of their row are dropped after inflation
+const MCL_PRUNE: f64 = 1e-4;
+/// Iterations stop once no entry changes by more than this
+const MCL_TOLERANCE: f64 = 1e-6;
+
+/// Markov clustering of the alignment graph, as run by the original GCM before tracing.
+/// Self-loops get the largest weight of their node, and the matrix is kept row-stochastic,
+/// alternating expansion (squaring) and inflation (entrywise power `inflation`, then
+/// normalization). Returns the cluster id of every node; the clusters are unordered and
+/// may contain several columns of the same constraint.
+pub fn mcl(graph: &Graph, inflation: f64) -> Vec<usize> {
+    let n = graph.size;
+    let mut triplets = TriMat::with_capacity((n, n), graph.sims.nnz() + n);
+    for (&w, (u, v)) in graph.sims.iter() {
+        triplets.add_triplet(u, v, w);
+    }
+    for u in 0..n {
+        let max = graph.neighbors(u).map(|(_, w)| w).fold(0.0, f64::max);
+        triplets.add_triplet(u, u, if max > 0.0 { max } else { 1.0 });
+    }
+    let mut m = inflate(&triplets.to_csr(), 1.0);
+    for iteration in 0..MCL_MAX_ITERATIONS {
+        let next = inflate(&(&m * &m), inflation);
+        let change = (&next - &m)
+            .data()
+            .iter()
+            .fold(0.0f64, |acc, x| acc.max(x.abs()));
+        m = next;
+        debug!(
+            "MCL iteration {}: {} entries, largest change {}",
+            iteration + 1,
+            m.nnz(),
+            change
+        );
+        if change < MCL_TOLERANCE {
+            break;
+        }
+    }
+    // every node joins its attractor, the largest entry of its row
+    let mut attractors: UnionFind<usize> = UnionFind::new(n);
+    for (u, row) in m.outer_iterator().enumerate() {
+        let best = row.iter().fold(
+            (u, 0.0),
+            |best, (v, &x)| if x > best.1 { (v, x) } else { best },
+        );
+        attractors.union(u, best.0);
+    }
+    let mut ids: AHashMap<usize, usize> = AHashMap::default();
+    (0..n)
+        .map(|u| {
+            let root = attractors.find(u);
+            let next = ids.len();
+            *ids.entry(root).or_insert(next)
+        })
+        .collect()
+}
+
+/// The MCL clusters of the nodes with edges, in no particular order.
+pub fn mcl_clusters(graph: &Graph, inflation: f64) -> ClusteringResult {
+    let groups = mcl(graph, inflation);
+    let count = groups.iter().max().map_or(0, |&g| g + 1);
+    let mut clusters: Vec<Vec<(u32, u32)>> = vec![vec![]; count];
+    for &u in &graph.labels {
+        clusters[groups[u]].push(graph.node_pos[u]);
+    }
+    clusters.retain(|cluster| !cluster.is_empty());
+    ClusteringResult { clusters }
+}
+
+/// Raises the entries to the power `r`, prunes the small ones and normalizes the rows.
+fn inflate(m: &CsMat<f64>, r: f64) -> CsMat<f64> {
+    let mut triplets = TriMat::with_capacity(m.shape(), m.nnz());
+    for (u, row) in m.outer_iterator().enumerate() {
+        let powered: Vec<(usize, f64)> = row.iter().map(|(v, &x)| (v, x.powf(r))).collect();
+        let total: f64 = powered.iter().map(|&(_, x)| x).sum();
+        if total <= 0.0 {
+            continue;
+        }
+        let kept: Vec<(usize, f64)> = powered
+            .into_iter()
+            .filter(|&(_, x)| x / total >= MCL_PRUNE)
+            .collect();
+        let kept_total: f64 = kept.iter().map(|&(_, x)| x).sum();
+        for (v, x) in kept {
+            triplets.add_triplet(u, v, x / kept_total);
+        }
+    }
+    triplets.to_csr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disconnected_cliques_form_two_clusters() {
+        // columns (c, i) of three constraints with two columns each, node 2c + i
+        let node_pos = (0..6).map(|u| ((u / 2) as u32, (u % 2) as u32)).collect();
+        let mut upper = TriMat::new((6, 6));
+        for clique in [[0, 2, 4], [1, 3, 5]] {
+            for (k, &u) in clique.iter().enumerate() {
+                for &v in &clique[k + 1..] {
+                    upper.add_triplet(u, v, 1.0);
+                }
+            }
+        }
+        let graph = Graph::from_upper(&upper.to_csr(), node_pos);
+        let groups = mcl(&graph, 2.0);
+        assert_eq!(groups[0], groups[2]);
+        assert_eq!(groups[0], groups[4]);
+        assert_eq!(groups[1], groups[3]);
+        assert_eq!(groups[1], groups[5]);
+        assert_ne!(groups[0], groups[1]);
+        assert_eq!(mcl_clusters(&graph, 2.0).clusters.len(), 2);
+    }
+}
@@ -1,30 +1,44 @@
 use ahash::AHashMap;
+use anyhow::Context;
 use ordered_float::NotNan;
 
-use crate::{aln::AlnProcessor, external::request_alignment};
+use crate::{
+    aln::{record_lines, AlnProcessor},
+    error::GcmError,
+    external::Aligner,
+    input::open_fasta,
+    output::{alignment_writer, create_output, OutputFormat},
+    report::StageRecorder,
+    residues::{first_mismatch, residue_map, ResidueMode},
+};
 
 use itertools::Itertools;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use seq_io::{fasta::Reader, BaseRecord};
+use seq_io::BaseRecord;
+use sprs::{CsMat, TriMat};
 use std::{
-    collections::BTreeSet,
     error::Error,
-    fs::File,
-    io::{BufWriter, Write},
     path::PathBuf,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::{sync::Semaphore, task};
 
 use crate::{cluster::ClusteringResult, cluster::Graph, state::AlnState};
 
-pub fn state_from_constraints(constraint_alns: &[PathBuf]) -> anyhow::Result<AlnState> {
-    let mut p = StateFromConstraints::default();
+/// Reads the constraints into an alignment state, keeping the residues of every sequence only if
+/// `keep_residues` is set, as only glue residue checks and alignment comparisons need them.
+pub fn state_from_constraints(
+    constraint_alns: &[PathBuf],
+    keep_residues: bool,
+) -> anyhow::Result<AlnState> {
+    let mut p = StateFromConstraints::new(keep_residues);
     for aln in constraint_alns {
         // cid : constraint id
-        let mut reader = Reader::from_path(aln)?;
+        p.set_file(aln);
+        let mut reader = open_fasta(aln)?;
         while let Some(result) = reader.next() {
-            let rec = result?;
+            let rec = result.with_context(|| format!("reading {}", aln.display()))?;
             p.on_record(&rec)?;
         }
         p.next_aln();
@@ -37,25 +51,35 @@ pub struct StateFromConstraints {
     sequence_id: usize,
     columns: usize,
     cid: usize,
+    file: PathBuf,
+    line: usize,
+    keep_residues: bool,
 }
 
 impl StateFromConstraints {
-    pub fn next_aln(&mut self) {
-        self.state.column_counts.push(self.columns);
-        self.columns = 0;
-        self.cid += 1;
-    }
-}
-
-impl Default for StateFromConstraints {
-    fn default() -> Self {
+    pub fn new(keep_residues: bool) -> Self {
         Self {
             state: AlnState::new(),
             sequence_id: 0,
             columns: 0,
             cid: 0,
+            file: PathBuf::new(),
+            line: 1,
+            keep_residues,
         }
     }
+
+    /// Sets the file the following records come from, for error reporting.
+    pub fn set_file(&mut self, file: &PathBuf) {
+        self.file = file.clone();
+        self.line = 1;
+    }
+
+    pub fn next_aln(&mut self) {
+        self.state.column_counts.push(self.columns);
+        self.columns = 0;
+        self.cid += 1;
+    }
 }
 
 impl AlnProcessor for StateFromConstraints {
@@ -64,24 +88,46 @@ impl AlnProcessor for StateFromConstraints {
     fn on_record(&mut self, rec: &seq_io::fasta::RefRecord) -> anyhow::Result<()> {
         let name = String::from_utf8(rec.head().iter().copied().collect_vec())?;
         let mut column = 0usize;
-        self.state.names.push(name.clone());
-        self.state.names2id.insert(name, self.sequence_id);
+        let line = self.line;
+        self.line += record_lines(rec);
+        if self.state.names2id.contains_key(&name) {
+            return Err(GcmError::DuplicateName {
+                name,
+                file: self.file.clone(),
+                line,
+            }
+            .into());
+        }
         // res.id2constraint
         let mut s_slice: Vec<(u32, u32)> = vec![];
+        let mut residues: Vec<u8> = vec![];
         for l in rec.seq_lines() {
             for &c in l {
                 if c != b'-' {
                     s_slice.push((self.cid as u32, column as u32));
+                    if self.keep_residues {
+                        residues.push(c.to_ascii_uppercase());
+                    }
                 }
                 column += 1;
             }
         }
-        if self.columns <= 0 {
+        if self.columns == 0 {
             self.columns = column;
-        } else {
-            assert_eq!(column, self.columns);
+        } else if column != self.columns {
+            return Err(GcmError::RaggedAlignment {
+                name,
+                file: self.file.clone(),
+                line,
+                expected: self.columns,
+                found: column,
+            }
+            .into());
         }
+        self.state.names.push(name.clone());
+        self.state.names2id.insert(name, self.sequence_id);
         self.state.s.push(s_slice);
+        self.state.residues.push(residues);
         self.sequence_id += 1;
         Ok(())
     }
@@ -93,35 +139,92 @@ impl AlnProcessor for StateFromConstraints {
 
 type SparseGraph = AHashMap<(u32, u32), AHashMap<(u32, u32), f64>>;
 
-pub fn build_subgraph(state: &AlnState, glue: &PathBuf) -> anyhow::Result<SparseGraph> {
+pub fn build_subgraph(
+    state: &AlnState,
+    glue: &PathBuf,
+    residue_mode: ResidueMode,
+) -> anyhow::Result<SparseGraph> {
     let s = &state.s;
     let mut res = AHashMap::default();
     let mut colors: Vec<AHashMap<(u32, u32), usize>> = vec![];
-    let mut reader = Reader::from_path(glue)?;
+    let mut reader = open_fasta(glue)?;
+    let mut line = 1;
     while let Some(result) = reader.next() {
-        let rec = result?;
+        let rec = result.with_context(|| format!("reading {}", glue.display()))?;
         let name = String::from_utf8(rec.head().iter().copied().collect_vec())?;
+        let record_line = line;
+        line += record_lines(&rec);
+        let id = *state
+            .names2id
+            .get(&name)
+            .ok_or_else(|| GcmError::UnknownTaxon {
+                name: name.clone(),
+                file: glue.clone(),
+                line: record_line,
+            })?;
         let mut column = 0;
-        let mut first_ele = false;
-        if colors.is_empty() {
-            first_ele = true;
-        }
-        let mut non_gap = 0;
+        // (column, residue) of the non-gap characters
+        let mut residues: Vec<(usize, u8)> = vec![];
         for l in rec.seq_lines() {
             for &c in l {
-                if first_ele {
-                    colors.push(AHashMap::default());
-                }
                 if c != b'-' {
-                    let id = state.names2id[&name];
-                    let c = s[id][non_gap];
-                    let entry = colors[column].entry(c).or_default();
-                    *entry += 1;
-                    non_gap += 1;
+                    residues.push((column, c));
                 }
                 column += 1;
             }
         }
+        if colors.is_empty() {
+            colors.resize_with(column, AHashMap::default);
+        } else if column != colors.len() {
+            return Err(GcmError::RaggedAlignment {
+                name,
+                file: glue.clone(),
+                line: record_line,
+                expected: colors.len(),
+                found: column,
+            }
+            .into());
+        }
+        // the residues themselves are only kept when the residue mode compares them
+        let constraint_residues = &state.residues[id];
+        let constraint_len = s[id].len();
+        let glue_residues = residues.iter().map(|(_, c)| *c).collect_vec();
+        let mismatch = |position: usize| GcmError::ResidueMismatch {
+            name: name.clone(),
+            file: glue.clone(),
+            line: record_line,
+            position: position + 1,
+            glue_residues: glue_residues.len(),
+            constraint_residues: constraint_len,
+        };
+        // None stands for the identity mapping
+        let mapping = match residue_mode {
+            ResidueMode::Trust => {
+                if glue_residues.len() > constraint_len {
+                    return Err(mismatch(constraint_len).into());
+                }
+                None
+            }
+            ResidueMode::Verify => {
+                if let Some(p) = first_mismatch(&glue_residues, constraint_residues) {
+                    return Err(mismatch(p).into());
+                }
+                None
+            }
+            ResidueMode::Reconcile => first_mismatch(&glue_residues, constraint_residues)
+                .map(|_| residue_map(&glue_residues, constraint_residues)),
+        };
+        for (non_gap, &(column, _)) in residues.iter().enumerate() {
+            let target = match &mapping {
+                None => Some(non_gap),
+                Some(m) => m[non_gap],
+            };
+            if let Some(t) = target {
+                let c = s[id][t];
+                let entry = colors[column].entry(c).or_default();
+                *entry += 1;
+            }
+        }
     }
     for c in &colors {
         for (c1, c2) in c.keys().tuple_combinations() {
@@ -134,72 +237,100 @@ pub fn build_subgraph(state: &AlnState, glue: &PathBuf) -> anyhow::Result<Sparse
     Ok(res)
 }
 
+/// Builds the alignment graph from the glues, recording the time spent on each glue
+/// subgraph and on merging them into `stages`, the latter with the peak memory of the
+/// whole construction.
 pub fn build_graph(
     state: &AlnState,
     glues: &[PathBuf],
     weights: &Option<Vec<NotNan<f64>>>,
+    residue_mode: ResidueMode,
+    streaming: bool,
+    stages: &StageRecorder,
 ) -> anyhow::Result<Graph> {
-    let subgraphs_: anyhow::Result<Vec<SparseGraph>> = glues
-        .par_iter()
-        .map(|glue| build_subgraph(state, glue))
-        .collect();
-    let subgraphs = subgraphs_?;
-    // now we need to merge the subgraphs
-    let mut merged: AHashMap<usize, AHashMap<usize, f64>> = AHashMap::default();
+    if let Some(w) = weights {
+        if w.len() != glues.len() {
+            return Err(GcmError::WeightCountMismatch {
+                weights: w.len(),
+                glues: glues.len(),
+            }
+            .into());
+        }
+    }
+    let (pos2id, node_pos) = index_columns(state);
+    let n = node_pos.len();
+    let glue_weight = |i: usize| weights.as_ref().map(|w| w[i].into_inner()).unwrap_or(1.0);
+    // the subgraphs may be built in parallel, so only the peak memory of the whole
+    // graph construction is recorded, along with the merging
+    stages.reset_peak();
+    let glue_matrix = |i: usize, glue: &PathBuf| -> anyhow::Result<CsMat<f64>> {
+        let now = Instant::now();
+        let subgraph = build_subgraph(state, glue, residue_mode)?;
+        let matrix = subgraph_matrix(&subgraph, &pos2id, n, glue_weight(i));
+        stages.record_time(format!("subgraph {}", glue.display()), now.elapsed());
+        Ok(matrix)
+    };
+    // time spent adding up the matrices, summed over the threads
+    let merge_time = Mutex::new(Duration::ZERO);
+    let add = |a: CsMat<f64>, b: CsMat<f64>| -> CsMat<f64> {
+        let now = Instant::now();
+        let sum = &a + &b;
+        *merge_time.lock().unwrap() += now.elapsed();
+        sum
+    };
+    let merged = if streaming {
+        // fold each glue into the accumulator as soon as it is read, so that
+        // at most one glue subgraph is alive at any time
+        let mut merged: CsMat<f64> = CsMat::zero((n, n));
+        for (i, glue) in glues.iter().enumerate() {
+            merged = add(merged, glue_matrix(i, glue)?);
+        }
+        merged
+    } else {
+        glues
+            .par_iter()
+            .enumerate()
+            .map(|(i, glue)| glue_matrix(i, glue))
+            .try_reduce(|| CsMat::zero((n, n)), |a, b| Ok(add(a, b)))?
+    };
+    let now = Instant::now();
+    let graph = Graph::from_upper(&merged, node_pos);
+    stages.record(
+        "merge graph",
+        merge_time.into_inner().unwrap() + now.elapsed(),
+    );
+    Ok(graph)
+}
+
+/// Numbers the columns of all constraints consecutively, returning the mapping from
+/// column positions to node ids and its inverse.
+fn index_columns(state: &AlnState) -> (AHashMap<(u32, u32), usize>, Vec<(u32, u32)>) {
     let mut pos2id: AHashMap<(u32, u32), usize> = AHashMap::default();
-    let mut labels: BTreeSet<usize> = BTreeSet::default();
     let mut node_pos: Vec<(u32, u32)> = vec![];
-    let mut id = 0;
     for (c, &l) in state.column_counts.iter().enumerate() {
         for i in 0..l {
-            pos2id.insert((c as u32, i as u32), id);
+            pos2id.insert((c as u32, i as u32), node_pos.len());
             node_pos.push((c as u32, i as u32));
-            id += 1;
         }
     }
-    for (i, subgraph) in subgraphs.iter().enumerate() {
-        let subgraph_weight = weights
-            .as_ref()
-            .map(|w| w[i as usize].into_inner())
-            .unwrap_or(1.0);
-        for (u, map) in subgraph {
-            for (v, w) in map {
-                let u = pos2id[u];
-                let v = pos2id[v];
-                labels.insert(u);
-                labels.insert(v);
-                let entry = merged.entry(u).or_insert_with(AHashMap::default);
-                let entry2 = entry.entry(v).or_default();
-                *entry2 += w * subgraph_weight;
-            }
+    (pos2id, node_pos)
+}
+
+/// Converts a glue subgraph into an (upper-triangular) `n` by `n` CSR matrix over node ids, scaled by `weight`.
+fn subgraph_matrix(
+    subgraph: &SparseGraph,
+    pos2id: &AHashMap<(u32, u32), usize>,
+    n: usize,
+    weight: f64,
+) -> CsMat<f64> {
+    let nnz = subgraph.values().map(|m| m.len()).sum();
+    let mut triplets = TriMat::with_capacity((n, n), nnz);
+    for (u, map) in subgraph {
+        for (v, w) in map {
+            triplets.add_triplet(pos2id[u], pos2id[v], w * weight);
         }
     }
-
-    // let mut og: AHashMap<(u32, u32), AHashMap<(u32, u32), f64>> = AHashMap::default();
-    // for (i, subgraph) in subgraphs.iter().enumerate() {
-    //     let subgraph_weight = weights
-    //         .as_ref()
-    //         .map(|w| w[i as usize].into_inner())
-    //         .unwrap_or(1.0);
-    //     for (u, map) in subgraph {
-    //         for (v, w) in map {
-    //             let u = *u;
-    //             let v = *v;
-    //             let entry = og.entry(u).or_insert_with(AHashMap::default);
-    //             let entry2 = entry.entry(v).or_default();
-    //             *entry2 += w * subgraph_weight;
-    //         }
-    //     }
-    // }
-
-    // exact_solver::solve_twocase_mwt(&mut og);
-
-    Ok(Graph {
-        size: id,
-        labels: labels.into_iter().collect_vec(),
-        sims: merged,
-        node_pos,
-    })
+    triplets.to_csr()
 }
 
 pub fn build_frames(state: &AlnState, res: &ClusteringResult) -> Vec<Vec<u32>> {
@@ -257,10 +388,11 @@ pub fn build_frames(state: &AlnState, res: &ClusteringResult) -> Vec<Vec<u32>> {
     }
 
     for c in 0..k {
-        let expected_len = state.column_counts[c] - 1;
-        if (last_frontier[c as usize] as usize) < expected_len {
+        let expected_len = state.column_counts[c] as i64 - 1;
+        if last_frontier[c as usize] < expected_len {
             // then singletons exist, we need to take care of them
-            let num_singletons = (expected_len - last_frontier[c as usize] as usize) as usize;
+            // (all columns are singletons in a constraint without clustered columns)
+            let num_singletons = (expected_len - last_frontier[c as usize]) as usize;
             for j in 0..k {
                 if j == c as usize {
                     for _ in 0..num_singletons {
@@ -280,13 +412,14 @@ pub fn merge_alignments_from_frames(
     constraints: &[PathBuf],
     frames: &[Vec<u32>],
     outfile: &PathBuf,
+    format: OutputFormat,
+    line_width: usize,
 ) -> anyhow::Result<()> {
-    let out = File::create(outfile).unwrap();
-    let mut writer = BufWriter::new(out);
+    let mut writer = alignment_writer(format, line_width, create_output(outfile)?);
     for (constraint, frame) in constraints.iter().zip(frames) {
-        let mut reader = Reader::from_path(constraint)?;
+        let mut reader = open_fasta(constraint)?;
         while let Some(result) = reader.next() {
-            let rec = result?;
+            let rec = result.with_context(|| format!("reading {}", constraint.display()))?;
             let mut buf: Vec<u8> = vec![];
             let mut char_count = 0;
             let mut written = 0;
@@ -301,41 +434,43 @@ pub fn merge_alignments_from_frames(
                 }
             }
             if char_count < frame.len() {
-                for _ in 0..(frame[frame.len() - 1] + 1) {
+                for _ in 0..frame[frame.len() - 1] {
                     buf.push(b'-');
                 }
             }
             // println!("buflen: {}, sum of frame: {}, {}/{}, {}", buf.len(), written, char_count, frame.len(), frame[frame.len() - 1]);
-            writer.write_all(b">")?;
-            writer.write_all(rec.head())?;
-            writer.write_all(b"\n")?;
-            buf.chunks(60)
-                .try_for_each::<_, anyhow::Result<()>>(|chunk| {
-                    writer.write_all(chunk)?;
-                    writer.write_all(b"\n")?;
-                    Ok(())
-                })?;
+            writer.write_record(rec.head(), &buf)?;
         }
     }
-    Ok(())
+    writer.finish()
 }
 
-pub async fn align_glues(glues: &[PathBuf], tokens: usize) -> Vec<PathBuf> {
+/// Aligns unaligned subsets (constraints or glues) with `aligner`, running at most `tokens`
+/// jobs at once, and returns the paths of the alignments (the inputs with an `aln` extension).
+pub async fn align_subsets(
+    subsets: &[PathBuf],
+    aligner: Arc<dyn Aligner>,
+    tokens: usize,
+) -> anyhow::Result<Vec<PathBuf>> {
     let mut join_handles = vec![];
     let semaphore = Arc::new(Semaphore::new(tokens));
     let mut outputs = vec![];
-    for g in glues {
+    for g in subsets {
         let cg = g.clone();
-        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let permit = semaphore.clone().acquire_owned().await?;
         let mut outpath = g.clone();
         outpath.set_extension("aln");
         let opc = outpath.clone();
+        let aligner = aligner.clone();
         join_handles.push(task::spawn_blocking(move || {
-            request_alignment(&cg, &outpath).expect("failed to align");
+            let res = aligner.align(&cg, &outpath);
             drop(permit);
+            res
         }));
         outputs.push(opc);
     }
-    futures::future::join_all(join_handles).await;
-    outputs
+    for res in futures::future::join_all(join_handles).await {
+        res??;
+    }
+    Ok(outputs)
 }
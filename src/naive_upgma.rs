@@ -1,6 +1,6 @@
 use std::collections::{BTreeSet, BinaryHeap, VecDeque};
 
-use crate::cluster::{ClusteringResult, Graph};
+use crate::cluster::{ClusteringResult, Graph, Linkage};
 use crate::{cluster::reorder, state::AlnState};
 use ahash::{AHashMap, AHashSet};
 use fixedbitset::FixedBitSet;
@@ -21,100 +21,130 @@ pub fn triplets_to_sims(
     sims
 }
 
-pub fn union(lhs: &mut Vec<usize>, rhs: &[usize]) {
-    let mut set = BTreeSet::from_iter(lhs.drain(0..));
-    set.extend(rhs.iter());
-    lhs.extend(set.into_iter());
+/// Clusters in a topological order of the "column precedes column" relation, maintained
+/// incrementally as clusters are contracted, following Pearce and Kelly. Whether contracting two
+/// clusters creates a cycle only requires searching the clusters between them in the order.
+/// Edges are kept between initial clusters and resolved through the union-find.
+struct ClusterOrder {
+    ord: Vec<usize>,
+    outedges: Vec<Vec<usize>>,
+    inedges: Vec<Vec<usize>>,
+    /// `epoch` for the clusters visited by the current search
+    mark: Vec<usize>,
+    epoch: usize,
 }
 
-fn ds_dfs(outedges: &Vec<Vec<usize>>, visited: &mut FixedBitSet, u: usize, v: usize) -> bool {
-    let mut stack: Vec<usize> = vec![];
-    for e in &outedges[u] {
-        stack.push(*e);
-    }
-    while let Some(n) = stack.pop() {
-        for &e in &outedges[n] {
-            if !visited.contains(e) {
-                if e == v {
-                    return true;
-                }
-                stack.push(e);
-                visited.insert(e);
-            }
+impl ClusterOrder {
+    fn new(n: usize) -> Self {
+        Self {
+            ord: (0..n).collect(),
+            outedges: vec![Vec::new(); n],
+            inedges: vec![Vec::new(); n],
+            mark: vec![0; n],
+            epoch: 0,
         }
     }
-    false
-}
 
-fn order_clusters(absorbed: &FixedBitSet, outedges: &Vec<Vec<usize>>) -> Vec<usize> {
-    let n = outedges.len();
-    let mut indegs = vec![0; n];
-    for (ix, i) in outedges.iter().enumerate() {
-        if !absorbed.contains(ix) {
-            for &j in i {
-                indegs[j] += 1;
-            }
-        }
+    fn add_edge(&mut self, u: usize, v: usize) {
+        self.outedges[u].push(v);
+        self.inedges[v].push(u);
     }
 
-    let mut stack: Vec<usize> = vec![];
-    for (i, e) in indegs.iter().enumerate() {
-        if !absorbed.contains(i) && *e == 0 {
-            stack.push(i);
-        }
-    }
-
-    let mut order: Vec<usize> = vec![];
-    while let Some(t) = stack.pop() {
-        order.push(t);
-        for &j in &outedges[t] {
-            indegs[j] -= 1;
-            if indegs[j] == 0 {
-                stack.push(j);
+    /// Clusters reachable from `from` (itself first) in the given direction through clusters
+    /// strictly before (forward) or after (backward) `bound` in the order, `None` if `target` is reached.
+    fn search(
+        &mut self,
+        clusters: &UnionFind<usize>,
+        from: usize,
+        forward: bool,
+        bound: usize,
+        target: usize,
+    ) -> Option<Vec<usize>> {
+        self.epoch += 1;
+        let edges = if forward {
+            &self.outedges
+        } else {
+            &self.inedges
+        };
+        let mut found = vec![from];
+        let mut stack = vec![from];
+        self.mark[from] = self.epoch;
+        while let Some(x) = stack.pop() {
+            for &e in &edges[x] {
+                let y = clusters.find(e);
+                if y == target {
+                    return None;
+                }
+                let within = if forward {
+                    self.ord[y] < bound
+                } else {
+                    self.ord[y] > bound
+                };
+                if within && self.mark[y] != self.epoch {
+                    self.mark[y] = self.epoch;
+                    stack.push(y);
+                    found.push(y);
+                }
             }
         }
+        Some(found)
     }
-    order
-}
 
-pub fn disjoint_set_po_exists(
-    visited: &mut FixedBitSet,
-    outedges: &Vec<Vec<usize>>,
-    u: usize,
-    v: usize,
-    mid_visited: &FixedBitSet,
-) -> bool {
-    if outedges[u].contains(&v) && outedges[v].contains(&u) {
-        return false;
+    /// Whether the clusters `l` and `r` can be contracted without creating a cycle, in which case
+    /// the clusters after the first one (and before the second one) reachable from it, and those
+    /// reaching the second one, are returned for [`ClusterOrder::contract`].
+    fn try_contract(
+        &mut self,
+        clusters: &UnionFind<usize>,
+        l: usize,
+        r: usize,
+    ) -> Option<(Vec<usize>, Vec<usize>)> {
+        let (a, b) = if self.ord[l] < self.ord[r] {
+            (l, r)
+        } else {
+            (r, l)
+        };
+        let forward = self.search(clusters, a, true, self.ord[b], b)?;
+        let backward = self.search(clusters, b, false, self.ord[a], a)?;
+        Some((forward, backward))
     }
-    visited.clear();
 
-    match (mid_visited[u], mid_visited[v]) {
-        (true, true) => {
-            if ds_dfs(outedges, visited, u, v) || ds_dfs(outedges, visited, v, u) {
-                return false;
-            }
-        }
-        (false, false) => {
-            if ds_dfs(outedges, visited, u, v) || ds_dfs(outedges, visited, v, u) {
-                return false;
-            }
-        }
-        (true, false) => {
-            if ds_dfs(outedges, visited, v, u) {
-                return false;
-            }
-        }
-        (false, true) => {
-            if ds_dfs(outedges, visited, u, v) {
-                return false;
-            }
+    /// Contracts `m` into `n` given the result of [`ClusterOrder::try_contract`]: within the positions
+    /// of the searched clusters, those reaching the second cluster are moved before the contracted
+    /// one, and those reachable from the first one after it.
+    fn contract(&mut self, n: usize, m: usize, forward: Vec<usize>, backward: Vec<usize>) {
+        let mut pool = forward
+            .iter()
+            .chain(&backward)
+            .map(|&x| self.ord[x])
+            .collect_vec();
+        pool.sort_unstable();
+        let mut before = backward[1..].to_vec();
+        before.sort_unstable_by_key(|&x| self.ord[x]);
+        let mut after = forward[1..].to_vec();
+        after.sort_unstable_by_key(|&x| self.ord[x]);
+        let moved = before.into_iter().chain([n]).chain(after).collect_vec();
+        for (x, p) in moved.into_iter().zip(pool) {
+            self.ord[x] = p;
         }
+        let out = std::mem::take(&mut self.outedges[m]);
+        self.outedges[n].extend(out);
+        let inc = std::mem::take(&mut self.inedges[m]);
+        self.inedges[n].extend(inc);
     }
-    true
 }
 
-pub fn naive_upgma(graph: &Graph, state: &AlnState) -> ClusteringResult {
+pub fn naive_upgma(graph: &Graph, state: &AlnState, linkage: Linkage) -> ClusteringResult {
+    upgma_within(graph, state, linkage, None)
+}
+
+/// UPGMA only merging nodes of the same group (e.g., MCL clusters), if `groups` is given.
+pub fn upgma_within(
+    graph: &Graph,
+    state: &AlnState,
+    linkage: Linkage,
+    groups: Option<&[usize]>,
+) -> ClusteringResult {
     let n = graph.labels.len();
     let m = graph.size;
     let mut clusters: UnionFind<usize> = UnionFind::new(n);
@@ -123,30 +153,26 @@ pub fn naive_upgma(graph: &Graph, state: &AlnState) -> ClusteringResult {
     let mut cluster_sizes: Vec<usize> = vec![1; n];
     let mut node2init_cluster: Vec<usize> = vec![0; m];
     let mut weightmap: Vec<AHashMap<usize, f64>> = vec![AHashMap::new(); n];
-    let mut visited = FixedBitSet::with_capacity(n);
-    let mut mid_visited = FixedBitSet::with_capacity(n);
     for (i, l) in graph.labels.iter().enumerate() {
         node2init_cluster[*l] = i;
         rows[i].insert(graph.node_pos[*l].0);
     }
-    for (&u, map) in &graph.sims {
-        for (&v, &value) in map {
-            if u == v {
-                continue;
-            }
-            let mut lhs = node2init_cluster[u];
-            let mut rhs = node2init_cluster[v];
-            (lhs, rhs) = reorder(lhs, rhs);
-            weightmap[lhs].entry(rhs).or_insert(value);
-            weightmap[rhs].entry(lhs).or_insert(value);
-            pq.push((NotNan::new(value).unwrap(), lhs, rhs));
+    for (u, v, value) in graph.edges() {
+        if groups.map_or(false, |g| g[u] != g[v]) {
+            continue;
         }
+        let mut lhs = node2init_cluster[u];
+        let mut rhs = node2init_cluster[v];
+        (lhs, rhs) = reorder(lhs, rhs);
+        weightmap[lhs].entry(rhs).or_insert(value);
+        weightmap[rhs].entry(lhs).or_insert(value);
+        pq.push((NotNan::new(value).unwrap(), lhs, rhs));
     }
-    let mut order_outedges: Vec<Vec<usize>> = vec![Vec::new(); n];
-    let mut order_inedges: Vec<Vec<usize>> = vec![Vec::new(); n];
+    // consecutive labeled columns of the same constraint, in increasing order of node ids
+    let mut order = ClusterOrder::new(n);
     let mut bound = 0;
     let mut lengths = VecDeque::from_iter(state.column_counts.iter().copied());
-    for i in 0..(n - 1) {
+    for i in 0..n.saturating_sub(1) {
         let first_num = graph.labels[i];
         let second_num = graph.labels[i + 1];
 
@@ -155,8 +181,7 @@ pub fn naive_upgma(graph: &Graph, state: &AlnState) -> ClusteringResult {
         }
 
         if first_num < bound && second_num < bound {
-            order_outedges[node2init_cluster[first_num]].push(node2init_cluster[second_num]);
-            order_inedges[node2init_cluster[second_num]].push(node2init_cluster[first_num]);
+            order.add_edge(node2init_cluster[first_num], node2init_cluster[second_num]);
         }
     }
 
@@ -169,89 +194,50 @@ pub fn naive_upgma(graph: &Graph, state: &AlnState) -> ClusteringResult {
             continue;
         }
 
-        if invalidated.contains(&(l, r)) || v != weightmap[l][&r] {
-            continue;
-        }
-
-        if !rows[l].is_disjoint(&rows[r])
-            || !disjoint_set_po_exists(&mut visited, &order_outedges, l, r, &mid_visited)
-        {
-            weightmap[l].remove(&r);
-            weightmap[r].remove(&l);
-            invalidated.insert((l, r));
+        if invalidated.contains(&(l, r)) || weightmap[l].get(&r).map_or(true, |&w| v != w) {
             continue;
         }
 
-        // println!("printing outedges...");
-        // for (i, vec) in order_outedges.iter().enumerate() {
-        //     for v in BTreeSet::from_iter(vec.iter()) {
-        //         if !absorbed[i] && !absorbed[*v] {
-        //             println!("oe {:?}[{}] -> {:?}[{}]", graph.node_pos[i],i, graph.node_pos[*v],*v);
-        //         }
-        //     }
-        // }
-
-        // let x = order_outedges.iter().map(|it| it.len()).sum::<usize>();
-        // let y = order_inedges.iter().map(|it| it.len()).sum::<usize>();
-        // assert_eq!(x, y);
+        let contraction = if rows[l].is_disjoint(&rows[r]) {
+            order.try_contract(&clusters, l, r)
+        } else {
+            None
+        };
+        let (forward, backward) = match contraction {
+            Some(sets) => sets,
+            None => {
+                weightmap[l].remove(&r);
+                weightmap[r].remove(&l);
+                invalidated.insert((l, r));
+                continue;
+            }
+        };
 
-        // println!("Joining {:?}", (graph.node_pos[l], graph.node_pos[r]));
-        mid_visited = visited.clone();
-        // visited.clear();
         clusters.union(l, r);
         let n = clusters.find(l);
         let m = if l == n { r } else { l };
-        // println!("l={:?} r={:?} n={:?} m={:?}", l, r, n, m);
         absorbed.insert(m);
-        // println!("BEFORE: oe: n {:?} oe: m{:?}", order_outedges[n], order_outedges[m]);
-        // order_outedges.split_at()
-        order_outedges.push(vec![]);
-        let t1 = order_outedges.swap_remove(m);
-        union(order_outedges[n].as_mut(), &t1);
-        // println!("AFTER: oe: n {:?} oe: m{:?}", order_outedges[n], order_outedges[m]);
-        // union(order_inedges[n].as_mut(), order_outedges[n].as_mut());
-        // println!()
-
-        // println!("BEFORE: ie: n {:?} ie: m{:?}", order_inedges[n], order_inedges[m]);
-        order_inedges.push(vec![]);
-        let t2 = order_inedges.swap_remove(m);
-        union(order_inedges[n].as_mut(), &t2);
-        // println!("AFTER: ie: n {:?} ie: m{:?}", order_inedges[n], order_inedges[m]);
-
-        for &innode in &t2 {
-            for e in order_outedges[innode].iter_mut() {
-                if *e == m {
-                    *e = n;
-                }
-            }
-        }
-        for &outnode in &t1 {
-            for e in order_inedges[outnode].iter_mut() {
-                if *e == m {
-                    *e = n;
-                }
-            }
-        }
+        order.contract(n, m, forward, backward);
         let mut seen: BTreeSet<usize> = BTreeSet::new();
-        seen.extend(weightmap[l].keys().into_iter().chain(weightmap[r].keys()));
+        seen.extend(weightmap[l].keys().chain(weightmap[r].keys()));
         for c in seen {
-            if weightmap[l].contains_key(&c) && weightmap[r].contains_key(&c) {
-                let v1 = weightmap[l][&c];
-                let v2 = weightmap[r][&c];
-                let pt = weightmap[n].get_mut(&c).unwrap();
-                *pt = (v1 * cluster_sizes[l] as f64 + v2 * cluster_sizes[r] as f64)
-                    / (cluster_sizes[l] + cluster_sizes[r]) as f64;
-            } else if weightmap[l].contains_key(&c) {
-                let v1 = weightmap[l][&c];
-                weightmap[n].insert(c, v1);
-            } else {
-                let v2 = weightmap[r][&c];
-                weightmap[n].insert(c, v2);
+            if c == l || c == r {
+                continue;
+            }
+            let v1 = weightmap[l].get(&c).copied();
+            let v2 = weightmap[r].get(&c).copied();
+            match linkage.combine(v1, v2, cluster_sizes[l], cluster_sizes[r]) {
+                Some(v) => {
+                    weightmap[n].insert(c, v);
+                    weightmap[c].insert(n, v);
+                    let pair = reorder(c, n);
+                    pq.push((NotNan::new(v).unwrap(), pair.0, pair.1));
+                }
+                None => {
+                    weightmap[n].remove(&c);
+                    weightmap[c].remove(&n);
+                }
             }
-            let v = weightmap[n][&c];
-            weightmap[c].insert(n, v);
-            let pair = reorder(c, n);
-            pq.push((NotNan::new(v).unwrap(), pair.0, pair.1));
         }
 
         cluster_sizes[n] += cluster_sizes[m];
@@ -260,7 +246,8 @@ pub fn naive_upgma(graph: &Graph, state: &AlnState) -> ClusteringResult {
         rows[n] |= rm;
     } // end UPGMA
 
-    let cluster_ord = order_clusters(&absorbed, &order_outedges);
+    let mut cluster_ord = (0..n).filter(|&i| !absorbed[i]).collect_vec();
+    cluster_ord.sort_unstable_by_key(|&i| order.ord[i]);
     let mut final_clusters: AHashMap<usize, Vec<usize>> = AHashMap::new();
     for i in &graph.labels {
         let cid = clusters.find(node2init_cluster[*i]);
@@ -276,21 +263,6 @@ pub fn naive_upgma(graph: &Graph, state: &AlnState) -> ClusteringResult {
         );
     }
 
-    // let mut score = 0.0;
-    // for c in &cluster_ord {
-    //     let x = &final_clusters[c];
-    //     if x.len() <= 1 {
-    //         continue;
-    //     }
-    //     let n1 = x[0];
-    //     let n2 = x[1];
-    //     score += graph.sims[&n1][&n2];
-    // }
-    // println!("score: {}", score);
-
-    // for v in final_clusters.values() {
-
-    // }
     ClusteringResult {
         clusters: ordered_clusters,
     }
@@ -0,0 +1,253 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use clap::ArgEnum;
+
+use crate::{error::GcmError, input::is_stdio};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug, Hash)]
+pub enum OutputFormat {
+    Fasta,
+    /// Relaxed sequential PHYLIP
+    Phylip,
+    Stockholm,
+    Clustal,
+    Nexus,
+    /// FASTA with the columns gapped in the first sequence as lowercase insertions
+    A2m,
+    /// A2M without the gaps in insertion columns
+    A3m,
+}
+
+/// Creates the output file, or the standard output if `path` is `-`.
+pub fn create_output(path: &PathBuf) -> Result<Box<dyn Write>, GcmError> {
+    if is_stdio(path) {
+        return Ok(Box::new(BufWriter::new(std::io::stdout())));
+    }
+    let file = File::create(path).map_err(|source| GcmError::Io {
+        file: path.clone(),
+        source,
+    })?;
+    Ok(Box::new(BufWriter::new(file)))
+}
+
+/// A sink for the rows of an alignment.
+pub trait AlignmentWriter {
+    fn write_record(&mut self, name: &[u8], seq: &[u8]) -> anyhow::Result<()>;
+    /// Flushes the alignment; must be called after the last record.
+    fn finish(&mut self) -> anyhow::Result<()>;
+}
+
+/// Creates a writer of the given format. `width` is the number of alignment columns per line
+/// (0 for unwrapped output); PHYLIP and NEXUS are always written unwrapped.
+pub fn alignment_writer<W: Write + 'static>(
+    format: OutputFormat,
+    width: usize,
+    out: W,
+) -> Box<dyn AlignmentWriter> {
+    match format {
+        OutputFormat::Fasta => Box::new(FastaWriter {
+            out,
+            width,
+            reference: None,
+            mode: A2mMode::Plain,
+        }),
+        OutputFormat::A2m => Box::new(FastaWriter {
+            out,
+            width,
+            reference: None,
+            mode: A2mMode::A2m,
+        }),
+        OutputFormat::A3m => Box::new(FastaWriter {
+            out,
+            width,
+            reference: None,
+            mode: A2mMode::A3m,
+        }),
+        _ => Box::new(BufferedWriter {
+            out,
+            width,
+            format,
+            records: vec![],
+        }),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum A2mMode {
+    Plain,
+    A2m,
+    A3m,
+}
+
+fn is_gap(c: u8) -> bool {
+    c == b'-' || c == b'.'
+}
+
+/// Writes `seq` broken into lines of `width` characters (or a single line if `width` is 0).
+fn write_wrapped<W: Write>(out: &mut W, seq: &[u8], width: usize) -> std::io::Result<()> {
+    if width == 0 {
+        out.write_all(seq)?;
+        return out.write_all(b"\n");
+    }
+    seq.chunks(width).try_for_each(|chunk| {
+        out.write_all(chunk)?;
+        out.write_all(b"\n")
+    })
+}
+
+/// Streams FASTA-like formats. For A2M/A3M, the match columns are those
+/// where the first record has a residue.
+struct FastaWriter<W: Write> {
+    out: W,
+    width: usize,
+    reference: Option<Vec<bool>>,
+    mode: A2mMode,
+}
+
+impl<W: Write> AlignmentWriter for FastaWriter<W> {
+    fn write_record(&mut self, name: &[u8], seq: &[u8]) -> anyhow::Result<()> {
+        self.out.write_all(b">")?;
+        self.out.write_all(name)?;
+        self.out.write_all(b"\n")?;
+        if self.mode == A2mMode::Plain {
+            write_wrapped(&mut self.out, seq, self.width)?;
+            return Ok(());
+        }
+        let matches = self
+            .reference
+            .get_or_insert_with(|| seq.iter().map(|&c| !is_gap(c)).collect());
+        let mut row = Vec::with_capacity(seq.len());
+        for (&c, &is_match) in seq.iter().zip(matches.iter()) {
+            match (is_match, is_gap(c)) {
+                (true, true) => row.push(b'-'),
+                (true, false) => row.push(c.to_ascii_uppercase()),
+                (false, true) if self.mode == A2mMode::A2m => row.push(b'.'),
+                (false, true) => {}
+                (false, false) => row.push(c.to_ascii_lowercase()),
+            }
+        }
+        write_wrapped(&mut self.out, &row, self.width)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Formats with a header depending on all records, or interleaved blocks.
+struct BufferedWriter<W: Write> {
+    out: W,
+    width: usize,
+    format: OutputFormat,
+    records: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The name up to the first whitespace, as formats other than FASTA do not allow spaces in names.
+fn identifier(name: &[u8]) -> &[u8] {
+    name.split(|c| c.is_ascii_whitespace())
+        .next()
+        .unwrap_or(name)
+}
+
+impl<W: Write> BufferedWriter<W> {
+    fn write_padded(&mut self, name: &[u8], pad: usize, seq: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(name)?;
+        for _ in name.len()..pad {
+            self.out.write_all(b" ")?;
+        }
+        self.out.write_all(seq)?;
+        self.out.write_all(b"\n")
+    }
+
+    /// Writes the records in blocks of `width` columns (a single block if `width` is 0),
+    /// with the names padded to a common length.
+    fn write_blocks(&mut self) -> std::io::Result<()> {
+        let records = std::mem::take(&mut self.records);
+        let pad = records.iter().map(|(n, _)| n.len()).max().unwrap_or(0) + 2;
+        let ncols = records.first().map_or(0, |(_, s)| s.len());
+        let width = if self.width == 0 {
+            ncols.max(1)
+        } else {
+            self.width
+        };
+        for start in (0..ncols.max(1)).step_by(width) {
+            if start > 0 {
+                self.out.write_all(b"\n")?;
+            }
+            for (name, seq) in &records {
+                let end = (start + width).min(seq.len());
+                self.write_padded(name, pad, &seq[start.min(end)..end])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn nexus_datatype(&self) -> &'static str {
+        let nucleotides = b"ACGTUNRYKMSWBDHV?";
+        let is_dna = self.records.iter().all(|(_, seq)| {
+            seq.iter()
+                .all(|&c| is_gap(c) || nucleotides.contains(&c.to_ascii_uppercase()))
+        });
+        if is_dna {
+            "dna"
+        } else {
+            "protein"
+        }
+    }
+}
+
+impl<W: Write> AlignmentWriter for BufferedWriter<W> {
+    fn write_record(&mut self, name: &[u8], seq: &[u8]) -> anyhow::Result<()> {
+        self.records.push((identifier(name).to_vec(), seq.to_vec()));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let ntax = self.records.len();
+        let nchar = self.records.first().map_or(0, |(_, s)| s.len());
+        match self.format {
+            OutputFormat::Phylip => {
+                writeln!(self.out, "{} {}", ntax, nchar)?;
+                let records = std::mem::take(&mut self.records);
+                let pad = records.iter().map(|(n, _)| n.len()).max().unwrap_or(0) + 1;
+                for (name, seq) in &records {
+                    self.write_padded(name, pad, seq)?;
+                }
+            }
+            OutputFormat::Stockholm => {
+                writeln!(self.out, "# STOCKHOLM 1.0\n")?;
+                self.write_blocks()?;
+                writeln!(self.out, "//")?;
+            }
+            OutputFormat::Clustal => {
+                writeln!(self.out, "CLUSTAL W multiple sequence alignment\n")?;
+                self.write_blocks()?;
+            }
+            OutputFormat::Nexus => {
+                let datatype = self.nexus_datatype();
+                writeln!(self.out, "#NEXUS\nbegin data;")?;
+                writeln!(self.out, "  dimensions ntax={} nchar={};", ntax, nchar)?;
+                writeln!(
+                    self.out,
+                    "  format datatype={} missing=? gap=-;\n  matrix",
+                    datatype
+                )?;
+                let records = std::mem::take(&mut self.records);
+                let pad = records.iter().map(|(n, _)| n.len()).max().unwrap_or(0) + 2;
+                for (name, seq) in &records {
+                    self.write_padded(name, pad, seq)?;
+                }
+                writeln!(self.out, "  ;\nend;")?;
+            }
+            OutputFormat::Fasta | OutputFormat::A2m | OutputFormat::A3m => unreachable!(),
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use tracing::debug;
+
+use crate::{
+    cluster::{ClusteringResult, Graph},
+    exact_solver::sw_matches,
+    state::AlnState,
+};
+
+/// A partially merged alignment: a sequence of columns, each holding node ids.
+type Group = Vec<Vec<usize>>;
+
+/// Progressive pairwise tracing: starting with one group per constraint, the two groups
+/// with the largest total weight between them are repeatedly merged by the Smith-Waterman-like
+/// DP of [`sw_matches`], the matched columns collapsing into single columns of the new group.
+/// With two constraints, this is the same as [`crate::exact_solver::sw_algorithm`].
+pub fn progressive_sw(graph: &Graph, state: &AlnState) -> ClusteringResult {
+    let k = state.column_counts.len();
+    let offsets = state.column_offsets();
+    let mut groups: Vec<Option<Group>> = (0..k)
+        .map(|c| {
+            Some(
+                (0..state.column_counts[c])
+                    .map(|i| vec![offsets[c] + i])
+                    .collect(),
+            )
+        })
+        .collect();
+    // group and column index of every node
+    let mut location: Vec<(usize, usize)> = graph
+        .node_pos
+        .iter()
+        .map(|&(c, i)| (c as usize, i as usize))
+        .collect();
+    // total weight between every pair of groups
+    let mut between = vec![vec![0.0f64; k]; k];
+    for (u, v, w) in graph.edges() {
+        let (cu, cv) = (location[u].0, location[v].0);
+        if cu != cv {
+            between[cu][cv] += w;
+            between[cv][cu] += w;
+        }
+    }
+    for _ in 1..k {
+        let mut best: Option<(usize, usize)> = None;
+        for a in 0..k {
+            for b in (a + 1)..k {
+                if groups[a].is_none() || groups[b].is_none() {
+                    continue;
+                }
+                if best.map_or(true, |(x, y)| between[a][b] > between[x][y]) {
+                    best = Some((a, b));
+                }
+            }
+        }
+        let (a, b) = best.unwrap();
+        debug!(
+            "Merging groups {} and {} with total weight {} between them",
+            a, b, between[a][b]
+        );
+        let lhs = groups[a].take().unwrap();
+        let rhs = groups[b].take().unwrap();
+        let matches = sw_matches(lhs.len(), rhs.len(), |i, row| {
+            for &u in &lhs[i] {
+                for (v, w) in graph.neighbors(u) {
+                    let (g, j) = location[v];
+                    if g == b {
+                        row[j] += w;
+                    }
+                }
+            }
+        });
+        let merged = interleave(lhs, rhs, &matches);
+        for (j, column) in merged.iter().enumerate() {
+            for &u in column {
+                location[u] = (a, j);
+            }
+        }
+        for x in 0..k {
+            let w = between[b][x];
+            between[a][x] += w;
+            between[x][a] += w;
+            between[b][x] = 0.0;
+            between[x][b] = 0.0;
+        }
+        between[a][a] = 0.0;
+        groups[a] = Some(merged);
+    }
+    let clusters = groups
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|column| column.len() > 1)
+        .map(|mut column| {
+            column.sort_unstable();
+            column.into_iter().map(|u| graph.node_pos[u]).collect()
+        })
+        .collect();
+    ClusteringResult { clusters }
+}
+
+/// Merges two groups given their matched columns (in increasing order); unmatched
+/// columns between two matches are placed before the next match.
+fn interleave(lhs: Group, rhs: Group, matches: &[(usize, usize)]) -> Group {
+    let mut merged = Vec::with_capacity(lhs.len() + rhs.len() - matches.len());
+    let mut lhs = lhs.into_iter().enumerate().peekable();
+    let mut rhs = rhs.into_iter().enumerate().peekable();
+    for &(i, j) in matches {
+        while let Some((_, column)) = lhs.next_if(|(x, _)| *x < i) {
+            merged.push(column);
+        }
+        while let Some((_, column)) = rhs.next_if(|(y, _)| *y < j) {
+            merged.push(column);
+        }
+        let (_, mut column) = lhs.next().unwrap();
+        column.extend(rhs.next().unwrap().1);
+        merged.push(column);
+    }
+    merged.extend(lhs.map(|(_, column)| column));
+    merged.extend(rhs.map(|(_, column)| column));
+    merged
+}
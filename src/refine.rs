@@ -0,0 +1,328 @@
+use std::time::{Duration, Instant};
+
+use ahash::{AHashMap, AHashSet};
+use tracing::debug;
+
+use crate::{
+    cluster::{ClusteringResult, Graph},
+    state::AlnState,
+};
+
+/// Improvements smaller than this are ignored, so that rounding errors do not cause moves.
+const EPSILON: f64 = 1e-9;
+
+/// Limits of the refinement; it stops early once a pass makes no change.
+#[derive(Debug, Clone, Default)]
+pub struct RefineBudget {
+    /// number of passes over the nodes and the adjacent clusters, 0 for no limit if a time
+    /// limit is set and for no refinement otherwise
+    pub iterations: usize,
+    pub time: Option<Duration>,
+}
+
+impl RefineBudget {
+    /// Whether any refinement is asked for, i.e., a number of passes or a time limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.iterations > 0 || self.time.is_some()
+    }
+
+    fn max_passes(&self) -> usize {
+        match (self.iterations, self.time) {
+            (0, Some(_)) => usize::MAX,
+            (iterations, _) => iterations,
+        }
+    }
+}
+
+/// Improves a trace by local search. Each pass moves single nodes to the cluster (containing
+/// one of their neighbors) capturing the most weight, as long as the cluster lies between the
+/// clusters of the neighboring columns of the same constraint, then merges adjacent clusters
+/// over disjoint constraints with edges between them, and finally splits clusters by moving
+/// part of their nodes together to an adjacent cluster, which single node moves miss when
+/// these nodes are tightly connected. All of them keep the trace valid.
+pub fn refine(
+    graph: &Graph,
+    state: &AlnState,
+    res: ClusteringResult,
+    budget: &RefineBudget,
+) -> ClusteringResult {
+    let offsets = state.column_offsets();
+    // clusters in trace order, emptied rather than removed to keep their positions
+    let mut clusters: Vec<Vec<usize>> = res
+        .clusters
+        .iter()
+        .map(|cluster| {
+            cluster
+                .iter()
+                .map(|&(c, i)| offsets[c as usize] + i as usize)
+                .collect()
+        })
+        .collect();
+    let mut cluster_of: Vec<Option<usize>> = vec![None; graph.size];
+    for (x, cluster) in clusters.iter().enumerate() {
+        for &u in cluster {
+            cluster_of[u] = Some(x);
+        }
+    }
+    let start = Instant::now();
+    let out_of_time = || budget.time.map_or(false, |t| start.elapsed() >= t);
+    for iteration in 0..budget.max_passes() {
+        let mut gained = 0.0;
+        let mut moves = 0;
+        for &u in &graph.labels {
+            if out_of_time() {
+                break;
+            }
+            if let Some((x, gain)) = best_move(graph, state, &offsets, &clusters, &cluster_of, u) {
+                if let Some(old) = cluster_of[u] {
+                    clusters[old].retain(|&v| v != u);
+                }
+                clusters[x].push(u);
+                cluster_of[u] = Some(x);
+                gained += gain;
+                moves += 1;
+            }
+        }
+        let mut merges = 0;
+        let mut prev: Option<usize> = None;
+        for x in 0..clusters.len() {
+            if clusters[x].is_empty() {
+                continue;
+            }
+            if let Some(p) = prev {
+                let gain = merge_gain(graph, &clusters, &cluster_of, p, x);
+                if gain > EPSILON {
+                    let moved = std::mem::take(&mut clusters[x]);
+                    for &u in &moved {
+                        cluster_of[u] = Some(p);
+                    }
+                    clusters[p].extend(moved);
+                    gained += gain;
+                    merges += 1;
+                    continue;
+                }
+            }
+            prev = Some(x);
+        }
+        let mut splits = 0;
+        let order: Vec<usize> = (0..clusters.len())
+            .filter(|&x| !clusters[x].is_empty())
+            .collect();
+        for pair in order.windows(2) {
+            for (from, to) in [(pair[0], pair[1]), (pair[1], pair[0])] {
+                if clusters[from].is_empty() || clusters[to].is_empty() {
+                    continue;
+                }
+                if let Some((moved, gain)) = best_split(graph, &clusters, &cluster_of, from, to) {
+                    clusters[from].retain(|u| !moved.contains(u));
+                    for &u in &moved {
+                        cluster_of[u] = Some(to);
+                    }
+                    clusters[to].extend(moved);
+                    gained += gain;
+                    splits += 1;
+                }
+            }
+        }
+        debug!(
+            "Refinement pass {}: {} node moves, {} cluster merges and {} cluster splits, \
+             capturing {} more weight",
+            iteration + 1,
+            moves,
+            merges,
+            splits,
+            gained
+        );
+        if moves + merges + splits == 0 || out_of_time() {
+            break;
+        }
+    }
+    let clusters = clusters
+        .into_iter()
+        .filter(|cluster| !cluster.is_empty())
+        .map(|mut cluster| {
+            cluster.sort_unstable();
+            cluster.into_iter().map(|u| graph.node_pos[u]).collect()
+        })
+        .collect();
+    ClusteringResult { clusters }
+}
+
+/// The cluster that `u` gains the most weight by moving to, along with the gain, if positive.
+fn best_move(
+    graph: &Graph,
+    state: &AlnState,
+    offsets: &[usize],
+    clusters: &[Vec<usize>],
+    cluster_of: &[Option<usize>],
+    u: usize,
+) -> Option<(usize, f64)> {
+    let (c, i) = graph.node_pos[u];
+    let (c, i) = (c as usize, i as usize);
+    // u must stay between the clusters of the closest clustered columns of its constraint
+    let lower = (0..i).rev().find_map(|j| cluster_of[offsets[c] + j]);
+    let upper = (i + 1..state.column_counts[c]).find_map(|j| cluster_of[offsets[c] + j]);
+    let mut weights: AHashMap<usize, f64> = AHashMap::default();
+    for (v, w) in graph.neighbors(u) {
+        if let Some(x) = cluster_of[v] {
+            *weights.entry(x).or_default() += w;
+        }
+    }
+    let current = cluster_of[u].map_or(0.0, |x| weights.get(&x).copied().unwrap_or(0.0));
+    weights
+        .into_iter()
+        .filter(|&(x, _)| {
+            Some(x) != cluster_of[u]
+                && lower.map_or(true, |l| l < x)
+                && upper.map_or(true, |h| x < h)
+                && clusters[x]
+                    .iter()
+                    .all(|&v| graph.node_pos[v].0 as usize != c)
+        })
+        .map(|(x, w)| (x, w - current))
+        .filter(|&(_, gain)| gain > EPSILON)
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(b.0.cmp(&a.0)))
+}
+
+/// Weight captured by merging the consecutive clusters `x` and `y`, `0` if they share a constraint.
+fn merge_gain(
+    graph: &Graph,
+    clusters: &[Vec<usize>],
+    cluster_of: &[Option<usize>],
+    x: usize,
+    y: usize,
+) -> f64 {
+    let shared = clusters[x].iter().any(|&u| {
+        clusters[y]
+            .iter()
+            .any(|&v| graph.node_pos[u].0 == graph.node_pos[v].0)
+    });
+    if shared {
+        return 0.0;
+    }
+    clusters[x]
+        .iter()
+        .flat_map(|&u| graph.neighbors(u))
+        .filter(|&(v, _)| cluster_of[v] == Some(y))
+        .map(|(_, w)| w)
+        .sum()
+}
+
+/// The nodes of cluster `x` capturing the most weight by moving together to the adjacent
+/// cluster `y`, along with the gain, if positive. Starting from the nodes of `x` over
+/// constraints absent from `y`, the node whose removal gains the most is dropped until none
+/// is left, keeping the best set seen.
+fn best_split(
+    graph: &Graph,
+    clusters: &[Vec<usize>],
+    cluster_of: &[Option<usize>],
+    x: usize,
+    y: usize,
+) -> Option<(Vec<usize>, f64)> {
+    let nodes = &clusters[x];
+    let local: AHashMap<usize, usize> = nodes.iter().enumerate().map(|(k, &u)| (u, k)).collect();
+    let taken: AHashSet<u32> = clusters[y].iter().map(|&v| graph.node_pos[v].0).collect();
+    let mut moving: Vec<bool> = nodes
+        .iter()
+        .map(|&u| !taken.contains(&graph.node_pos[u].0))
+        .collect();
+    // weight from each node of x to y, and to the moving and the staying nodes of x
+    let mut to_y = vec![0.0; nodes.len()];
+    let mut to_moving = vec![0.0; nodes.len()];
+    let mut to_staying = vec![0.0; nodes.len()];
+    for (k, &u) in nodes.iter().enumerate() {
+        for (v, w) in graph.neighbors(u) {
+            if cluster_of[v] == Some(y) {
+                to_y[k] += w;
+            } else if let Some(&l) = local.get(&v) {
+                if moving[l] {
+                    to_moving[k] += w;
+                } else {
+                    to_staying[k] += w;
+                }
+            }
+        }
+    }
+    let mut gain: f64 = (0..nodes.len())
+        .filter(|&k| moving[k])
+        .map(|k| to_y[k] - to_staying[k])
+        .sum();
+    let mut best: Option<(Vec<usize>, f64)> = None;
+    loop {
+        if gain > best.as_ref().map_or(EPSILON, |b| b.1) {
+            let moved = (0..nodes.len())
+                .filter(|&k| moving[k])
+                .map(|k| nodes[k])
+                .collect();
+            best = Some((moved, gain));
+        }
+        let next = (0..nodes.len())
+            .filter(|&k| moving[k])
+            .map(|k| (k, to_staying[k] - to_moving[k] - to_y[k]))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let (k, delta) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        gain += delta;
+        moving[k] = false;
+        for (v, w) in graph.neighbors(nodes[k]) {
+            if let Some(&l) = local.get(&v) {
+                to_moving[l] -= w;
+                to_staying[l] += w;
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use sprs::TriMat;
+
+    use super::*;
+
+    #[test]
+    fn tightly_connected_nodes_are_split_off_together() {
+        let column_counts = [2, 1, 1, 1];
+        let mut state = AlnState::new();
+        state.column_counts = column_counts.to_vec();
+        let offsets = state.column_offsets();
+        let node_pos: Vec<(u32, u32)> = column_counts
+            .iter()
+            .enumerate()
+            .flat_map(|(c, &count)| (0..count).map(move |i| (c as u32, i as u32)))
+            .collect();
+        let node = |c: usize, i: usize| offsets[c] + i;
+        let n = node_pos.len();
+        let mut upper = TriMat::new((n, n));
+        for (u, v, w) in [
+            (node(0, 0), node(1, 0), 1.0),
+            (node(0, 0), node(2, 0), 1.0),
+            (node(1, 0), node(2, 0), 10.0),
+            (node(0, 1), node(1, 0), 3.0),
+            (node(0, 1), node(2, 0), 3.0),
+            (node(1, 0), node(3, 0), 3.0),
+            (node(2, 0), node(3, 0), 3.0),
+            (node(0, 1), node(3, 0), 10.0),
+        ] {
+            upper.add_triplet(u.min(v), u.max(v), w);
+        }
+        let graph = Graph::from_upper(&upper.to_csr(), node_pos);
+        // (1, 0) and (2, 0) belong with the second column of constraint 0 and (3, 0), but
+        // moving either of them alone loses the heavy edge between them
+        let res = ClusteringResult {
+            clusters: vec![vec![(0, 0), (1, 0), (2, 0)], vec![(0, 1), (3, 0)]],
+        };
+        let budget = RefineBudget {
+            iterations: 3,
+            time: None,
+        };
+        let refined = refine(&graph, &state, res, &budget);
+        assert_eq!(
+            refined.clusters,
+            vec![vec![(0, 0)], vec![(0, 1), (1, 0), (2, 0), (3, 0)]]
+        );
+        assert!(refined.violations(&column_counts).is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::{
+    cluster::{ClusteringResult, Graph},
+    error::GcmError,
+    state::AlnState,
+};
+
+/// Weight captured by a trace for the columns of a single constraint.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConstraintScore {
+    pub file: PathBuf,
+    pub columns: usize,
+    /// columns aligned to at least one column of another constraint
+    pub merged_columns: usize,
+    /// weight of the edges incident to the columns of this constraint kept by the trace
+    pub captured: f64,
+    /// weight of all edges incident to the columns of this constraint
+    pub incident: f64,
+}
+
+/// The MWT-AM objective achieved by a trace, i.e., the total weight of the edges
+/// within its clusters, along with the total weight of the graph as an upper bound.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceScore {
+    pub captured: f64,
+    pub upper_bound: f64,
+    pub clusters: usize,
+    pub constraints: Vec<ConstraintScore>,
+}
+
+impl TraceScore {
+    /// Fraction of the graph weight captured by the trace (1 for an empty graph).
+    pub fn ratio(&self) -> f64 {
+        if self.upper_bound > 0.0 {
+            self.captured / self.upper_bound
+        } else {
+            1.0
+        }
+    }
+
+    pub fn log(&self) {
+        info!(
+            "Trace captured {} of {} total graph weight ({:.2}%) in {} clusters",
+            self.captured,
+            self.upper_bound,
+            self.ratio() * 100.0,
+            self.clusters
+        );
+        for c in &self.constraints {
+            info!(
+                "{}: {} of {} columns merged, {} of {} incident weight captured",
+                c.file.display(),
+                c.merged_columns,
+                c.columns,
+                c.captured,
+                c.incident
+            );
+        }
+    }
+}
+
+/// Scores the trace `res` against the alignment graph.
+pub fn score_trace(
+    graph: &Graph,
+    state: &AlnState,
+    res: &ClusteringResult,
+    constraints: &[PathBuf],
+) -> TraceScore {
+    let offsets = state.column_offsets();
+    let mut cluster_of: Vec<Option<usize>> = vec![None; graph.size];
+    let mut merged_columns = vec![0usize; state.column_counts.len()];
+    for (i, cluster) in res.clusters.iter().enumerate() {
+        for &(c, col) in cluster {
+            cluster_of[offsets[c as usize] + col as usize] = Some(i);
+            if cluster.len() > 1 {
+                merged_columns[c as usize] += 1;
+            }
+        }
+    }
+    let mut captured = vec![0.0; state.column_counts.len()];
+    let mut incident = vec![0.0; state.column_counts.len()];
+    let (mut total_captured, mut upper_bound) = (0.0, 0.0);
+    for (u, v, w) in graph.edges() {
+        let (cu, cv) = (graph.node_pos[u].0 as usize, graph.node_pos[v].0 as usize);
+        upper_bound += w;
+        incident[cu] += w;
+        incident[cv] += w;
+        if cluster_of[u].is_some() && cluster_of[u] == cluster_of[v] {
+            total_captured += w;
+            captured[cu] += w;
+            captured[cv] += w;
+        }
+    }
+    TraceScore {
+        captured: total_captured,
+        upper_bound,
+        clusters: res.clusters.len(),
+        constraints: constraints
+            .iter()
+            .enumerate()
+            .map(|(c, file)| ConstraintScore {
+                file: file.clone(),
+                columns: state.column_counts[c],
+                merged_columns: merged_columns[c],
+                captured: captured[c],
+                incident: incident[c],
+            })
+            .collect(),
+    }
+}
+
+/// Writes `value` as pretty-printed JSON to `path`.
+pub fn write_json<T: Serialize>(path: &PathBuf, value: &T) -> anyhow::Result<()> {
+    let io_err = |source| GcmError::Io {
+        file: path.clone(),
+        source,
+    };
+    let mut writer = BufWriter::new(File::create(path).map_err(io_err)?);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush().map_err(io_err)?;
+    Ok(())
+}
+
+/// Time spent in a pipeline stage, and the peak memory of the process during the stage.
+#[derive(Debug, Clone, Serialize)]
+pub struct StageReport {
+    pub name: String,
+    pub seconds: f64,
+    /// peak resident set size during the stage, in kB (only known on Linux, and not for stages
+    /// running in parallel)
+    pub peak_rss_kb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphStats {
+    pub nodes: usize,
+    pub edges: usize,
+    /// nodes with at least one edge
+    pub labels: usize,
+}
+
+impl GraphStats {
+    pub fn new(graph: &Graph) -> Self {
+        Self {
+            nodes: graph.size,
+            edges: graph.edge_count(),
+            labels: graph.labels.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterStats {
+    pub clusters: usize,
+    /// clusters spanning more than one constraint
+    pub merged: usize,
+    pub largest: usize,
+}
+
+impl ClusterStats {
+    pub fn new(res: &ClusteringResult) -> Self {
+        Self {
+            clusters: res.clusters.len(),
+            merged: res.clusters.iter().filter(|c| c.len() > 1).count(),
+            largest: res.clusters.iter().map(|c| c.len()).max().unwrap_or(0),
+        }
+    }
+}
+
+/// Timings and sizes of a merging run, for tracking performance across datasets.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub stages: Vec<StageReport>,
+    pub graph: Option<GraphStats>,
+    pub clusters: Option<ClusterStats>,
+}
+
+impl RunReport {
+    /// Writes the report as TSV if `path` ends with `.tsv`, and as JSON otherwise.
+    pub fn write(&self, path: &PathBuf) -> anyhow::Result<()> {
+        let is_tsv = path
+            .extension()
+            .map_or(false, |e| e.eq_ignore_ascii_case("tsv"));
+        if !is_tsv {
+            return write_json(path, self);
+        }
+        let io_err = |source| GcmError::Io {
+            file: path.clone(),
+            source,
+        };
+        let mut out = BufWriter::new(File::create(path).map_err(io_err)?);
+        self.write_tsv(&mut out).map_err(io_err)?;
+        out.flush().map_err(io_err)?;
+        Ok(())
+    }
+
+    /// One `metric<TAB>value` row per measurement.
+    fn write_tsv<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "metric\tvalue")?;
+        for stage in &self.stages {
+            writeln!(out, "{}.seconds\t{}", stage.name, stage.seconds)?;
+            if let Some(kb) = stage.peak_rss_kb {
+                writeln!(out, "{}.peak_rss_kb\t{}", stage.name, kb)?;
+            }
+        }
+        if let Some(g) = &self.graph {
+            writeln!(out, "graph.nodes\t{}", g.nodes)?;
+            writeln!(out, "graph.edges\t{}", g.edges)?;
+            writeln!(out, "graph.labels\t{}", g.labels)?;
+        }
+        if let Some(c) = &self.clusters {
+            writeln!(out, "clusters.count\t{}", c.clusters)?;
+            writeln!(out, "clusters.merged\t{}", c.merged)?;
+            writeln!(out, "clusters.largest\t{}", c.largest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Peak resident set size of the process in kB since the last `reset_peak_rss`, read from
+/// `/proc/self/status`.
+fn peak_rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Resets the peak resident set size of the process to its current size (Linux 4.0 and later).
+/// If this fails, later peaks cover the whole run so far instead.
+fn reset_peak_rss() {
+    if let Err(e) = std::fs::write("/proc/self/clear_refs", "5") {
+        debug!("Could not reset the peak memory: {}", e);
+    }
+}
+
+/// Collects the stage timings of a run; stages may be recorded from several threads.
+pub struct StageRecorder {
+    start: Instant,
+    stages: Mutex<Vec<StageReport>>,
+}
+
+impl Default for StageRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StageRecorder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            stages: Mutex::new(vec![]),
+        }
+    }
+
+    /// Starts measuring the peak memory of the stage recorded next by `record`.
+    pub fn reset_peak(&self) {
+        reset_peak_rss();
+    }
+
+    /// Records the stage `name` along with the peak memory since the last `reset_peak`.
+    pub fn record(&self, name: impl Into<String>, elapsed: Duration) {
+        self.push(name.into(), elapsed, peak_rss_kb());
+    }
+
+    /// Records the stage `name` without its peak memory, for stages running alongside others.
+    pub fn record_time(&self, name: impl Into<String>, elapsed: Duration) {
+        self.push(name.into(), elapsed, None);
+    }
+
+    fn push(&self, name: String, elapsed: Duration, peak_rss_kb: Option<u64>) {
+        debug!("Stage {} took {:?}", name, elapsed);
+        self.stages.lock().unwrap().push(StageReport {
+            name,
+            seconds: elapsed.as_secs_f64(),
+            peak_rss_kb,
+        });
+    }
+
+    /// Runs `f` as the stage `name`, measuring its peak memory.
+    pub fn time<T>(&self, name: impl Into<String>, f: impl FnOnce() -> T) -> T {
+        self.reset_peak();
+        let now = Instant::now();
+        let res = f();
+        self.record(name, now.elapsed());
+        res
+    }
+
+    /// Closes the run with a `total` stage, whose peak memory is the largest of all stages.
+    pub fn finish(self, graph: Option<GraphStats>, clusters: Option<ClusterStats>) -> RunReport {
+        let elapsed = self.start.elapsed();
+        let mut stages = self.stages.into_inner().unwrap();
+        let peak = stages
+            .iter()
+            .filter_map(|s| s.peak_rss_kb)
+            .chain(peak_rss_kb())
+            .max();
+        stages.push(StageReport {
+            name: "total".to_string(),
+            seconds: elapsed.as_secs_f64(),
+            peak_rss_kb: peak,
+        });
+        RunReport {
+            stages,
+            graph,
+            clusters,
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use clap::ArgEnum;
+
+/// How the residues of a glue sequence are matched to the residues of the same taxon in its constraint.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug, Hash)]
+pub enum ResidueMode {
+    /// Assume that the i-th residue of the glue sequence is the i-th residue in the constraint
+    Trust,
+    /// Like `Trust`, but fail if the degapped sequences differ
+    Verify,
+    /// Align the residues of differing degapped sequences, dropping glue residues absent from the constraint
+    Reconcile,
+}
+
+impl ResidueMode {
+    /// Whether the residues of the constraint sequences are needed, not only their counts.
+    pub fn compares_residues(self) -> bool {
+        self != ResidueMode::Trust
+    }
+}
+
+/// Position (0-based) of the first residue differing between the two degapped sequences,
+/// including the case where one is a prefix of the other.
+pub fn first_mismatch(query: &[u8], target: &[u8]) -> Option<usize> {
+    let common = query.len().min(target.len());
+    match (0..common).find(|&i| !query[i].eq_ignore_ascii_case(&target[i])) {
+        Some(i) => Some(i),
+        None if query.len() != target.len() => Some(common),
+        None => None,
+    }
+}
+
+const MATCH: i32 = 2;
+const MISMATCH: i32 = -1;
+const GAP: i32 = -2;
+
+/// Maps each residue of `query` to a residue of `target` (or to `None` if left unaligned)
+/// by a global alignment that does not penalize unaligned residues at either end of `target`,
+/// as glue sequences may have been trimmed.
+pub fn residue_map(query: &[u8], target: &[u8]) -> Vec<Option<usize>> {
+    let n = query.len();
+    let m = target.len();
+    // back: 0 = diagonal, 1 = query residue unaligned, 2 = target residue unaligned
+    let mut back = vec![0u8; (n + 1) * (m + 1)];
+    let mut prev = vec![0i32; m + 1];
+    let mut cur = vec![0i32; m + 1];
+    for i in 1..=n {
+        cur[0] = prev[0] + GAP;
+        back[i * (m + 1)] = 1;
+        for j in 1..=m {
+            let diag = prev[j - 1]
+                + if query[i - 1].eq_ignore_ascii_case(&target[j - 1]) {
+                    MATCH
+                } else {
+                    MISMATCH
+                };
+            let up = prev[j] + GAP;
+            let left = cur[j - 1] + GAP;
+            let (score, pt) = if diag >= up && diag >= left {
+                (diag, 0)
+            } else if up >= left {
+                (up, 1)
+            } else {
+                (left, 2)
+            };
+            cur[j] = score;
+            back[i * (m + 1) + j] = pt;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    // trailing residues of the target are free: start from the best cell of the last row
+    let mut j = (0..=m).rev().max_by_key(|&j| prev[j]).unwrap();
+    let mut i = n;
+    let mut mapping = vec![None; n];
+    while i > 0 {
+        if j == 0 {
+            i -= 1;
+            continue;
+        }
+        match back[i * (m + 1) + j] {
+            0 => {
+                mapping[i - 1] = Some(j - 1);
+                i -= 1;
+                j -= 1;
+            }
+            1 => i -= 1,
+            _ => j -= 1,
+        }
+    }
+    mapping
+}
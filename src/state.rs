@@ -4,6 +4,8 @@ pub struct AlnState {
     pub names: Vec<String>,
     pub names2id: AHashMap<String, usize>, // TODO: there is no need to keep 2 copies of the same string
     pub s: Vec<Vec<(u32, u32)>>,
+    /// degapped, uppercased residues of each sequence (empty unless requested when reading the constraints)
+    pub residues: Vec<Vec<u8>>,
     pub column_counts: Vec<usize>,
 }
 
@@ -19,6 +21,18 @@ impl AlnState {
         *id
     }
 
+    /// Node id of the first column of each constraint, as the columns of all
+    /// constraints are numbered consecutively in the alignment graph.
+    pub fn column_offsets(&self) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(self.column_counts.len());
+        let mut total = 0;
+        for &count in &self.column_counts {
+            offsets.push(total);
+            total += count;
+        }
+        offsets
+    }
+
     pub fn retrieve(&self, name: &str) -> usize {
         *self.names2id.get(name).unwrap()
     }
@@ -28,6 +42,7 @@ impl AlnState {
             names: vec![],
             names2id: AHashMap::default(),
             s: vec![],
+            residues: vec![],
             column_counts: vec![],
         }
     }
@@ -7,7 +7,7 @@ use itertools::Itertools;
 use rand::{prelude::ThreadRng, Rng};
 use seq_io::{fasta::RefRecord, BaseRecord};
 
-use crate::aln::AlnProcessor;
+use crate::{aln::AlnProcessor, input::open_fasta};
 
 /// A streaming sequence sampler.
 pub struct SequenceSampler {
@@ -68,14 +68,19 @@ impl SequenceSampler {
 
     pub fn dump(&self, outfile: &PathBuf) -> anyhow::Result<()> {
         let mut writer = BufWriter::new(std::fs::File::create(outfile)?);
+        self.write_to(&mut writer)
+    }
+
+    /// Writes the sampled sequences as FASTA into `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
         for (name, seq) in self.names.iter().zip(self.records.iter()) {
             writer.write_all(b">")?;
             writer.write_all(name)?;
             writer.write_all(b"\n")?;
-            seq.chunks(60).for_each(|chunk| {
-                writer.write_all(chunk).unwrap();
-                writer.write_all(b"\n").unwrap();
-            });
+            seq.chunks(60).try_for_each(|chunk| {
+                writer.write_all(chunk)?;
+                writer.write_all(b"\n")
+            })?;
         }
         Ok(())
     }
@@ -98,7 +103,7 @@ pub fn sample_seqs_in_place(
     out: &PathBuf,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut sampler = SequenceSampler::new(Some(num_seqs));
-    let mut reader = seq_io::fasta::Reader::from_path(src)?;
+    let mut reader = open_fasta(src)?;
     while let Some(result) = reader.next() {
         let rec = result?;
         sampler.see(&rec);
@@ -0,0 +1,121 @@
+use std::{fmt, path::PathBuf};
+
+use ahash::AHashSet;
+use anyhow::Context;
+use ordered_float::NotNan;
+use seq_io::BaseRecord;
+
+use crate::{aln::record_lines, error::GcmError, input::open_fasta};
+
+/// All problems found in the inputs before merging.
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    pub problems: Vec<GcmError>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) found in the inputs:", self.problems.len())?;
+        for p in &self.problems {
+            write!(f, "\n  {}", p)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+/// Checks that the constraints are disjoint in taxa and not ragged, that the glues only contain
+/// taxa from the constraints, and that the weights (if any) match the glues one-to-one.
+pub fn validate_inputs(
+    constraints: &[PathBuf],
+    glues: &[PathBuf],
+    weights: &Option<Vec<NotNan<f64>>>,
+) -> anyhow::Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+    let mut taxa: AHashSet<String> = AHashSet::default();
+    for constraint in constraints {
+        scan_alignment(constraint, &mut report.problems, |name, line| {
+            if !taxa.insert(name.clone()) {
+                Some(GcmError::DuplicateName {
+                    name,
+                    file: constraint.clone(),
+                    line,
+                })
+            } else {
+                None
+            }
+        })?;
+    }
+    for glue in glues {
+        scan_alignment(glue, &mut report.problems, |name, line| {
+            if taxa.contains(&name) {
+                None
+            } else {
+                Some(GcmError::UnknownTaxon {
+                    name,
+                    file: glue.clone(),
+                    line,
+                })
+            }
+        })?;
+    }
+    if let Some(w) = weights {
+        if w.len() != glues.len() {
+            report.problems.push(GcmError::WeightCountMismatch {
+                weights: w.len(),
+                glues: glues.len(),
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Scans the records of an alignment, checking that it is not ragged and reporting every name
+/// (along with the line of its header) to `on_name`.
+fn scan_alignment<F>(
+    file: &PathBuf,
+    problems: &mut Vec<GcmError>,
+    mut on_name: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(String, usize) -> Option<GcmError>,
+{
+    let mut reader = match open_fasta(file) {
+        Ok(r) => r,
+        Err(e) => {
+            problems.push(e);
+            return Ok(());
+        }
+    };
+    let mut line = 1;
+    let mut columns: Option<usize> = None;
+    while let Some(result) = reader.next() {
+        let rec = result.with_context(|| format!("reading {}", file.display()))?;
+        let name = String::from_utf8_lossy(rec.head()).into_owned();
+        let record_line = line;
+        line += record_lines(&rec);
+        let len: usize = rec.seq_lines().map(|l| l.len()).sum();
+        match columns {
+            None => columns = Some(len),
+            Some(expected) if expected != len => problems.push(GcmError::RaggedAlignment {
+                name: name.clone(),
+                file: file.clone(),
+                line: record_line,
+                expected,
+                found: len,
+            }),
+            _ => {}
+        }
+        if let Some(e) = on_name(name, record_line) {
+            problems.push(e);
+        }
+    }
+    Ok(())
+}
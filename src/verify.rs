@@ -0,0 +1,406 @@
+use std::{fmt, path::PathBuf};
+
+use ahash::AHashMap;
+use anyhow::Context;
+
+use crate::{cluster::ClusteringResult, input::open_fasta, state::AlnState};
+
+/// A way in which a trace, or the merged alignment laid out from it, fails to align the
+/// constraints. Columns are given as `(constraint, column)`, clusters by their index in the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A column beyond the end of its constraint
+    OutOfRange { cluster: usize, column: (u32, u32) },
+    /// Two columns of the same constraint in one cluster
+    Duplicate {
+        cluster: usize,
+        constraint: u32,
+        columns: (u32, u32),
+    },
+    /// A column in two clusters
+    Repeated {
+        constraint: u32,
+        column: u32,
+        clusters: (usize, usize),
+    },
+    /// Two columns of a constraint whose clusters come in the opposite order in the trace
+    Crossing {
+        constraint: u32,
+        columns: (u32, u32),
+        clusters: (usize, usize),
+    },
+    /// A column of a constraint with no output column
+    Missing { constraint: u32, column: u32 },
+    /// A column sharing its output column with a column outside of its cluster, or not
+    /// in the output column of the rest of its cluster
+    Misplaced {
+        constraint: u32,
+        column: u32,
+        output_column: usize,
+    },
+    /// A constraint laid out over a different number of output columns than the first one
+    Width {
+        constraint: u32,
+        width: usize,
+        expected: usize,
+    },
+    /// A sequence of a constraint absent from the merged alignment
+    MissingSequence { constraint: u32, name: String },
+    /// A sequence of the merged alignment absent from the constraints
+    UnknownSequence { name: String },
+    /// A sequence appearing more than once in the merged alignment
+    DuplicateSequence { name: String },
+    /// A merged sequence whose length differs from the first merged sequence
+    Ragged {
+        name: String,
+        width: usize,
+        expected: usize,
+    },
+    /// A merged sequence whose residues differ from its constraint sequence
+    ResidueMismatch {
+        constraint: u32,
+        name: String,
+        /// 1-based index of the first differing residue
+        position: usize,
+    },
+    /// A column of a constraint whose residues are spread over several output columns
+    Split {
+        constraint: u32,
+        column: u32,
+        output_columns: (usize, usize),
+    },
+    /// Two columns of a constraint whose output columns are in the opposite order
+    Reordered {
+        constraint: u32,
+        columns: (u32, u32),
+        output_columns: (usize, usize),
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::OutOfRange { cluster, column } => write!(
+                f,
+                "cluster {} holds column {} of constraint {}, which has fewer columns",
+                cluster, column.1, column.0
+            ),
+            Violation::Duplicate {
+                cluster,
+                constraint,
+                columns,
+            } => write!(
+                f,
+                "cluster {} holds columns {} and {} of constraint {}",
+                cluster, columns.0, columns.1, constraint
+            ),
+            Violation::Repeated {
+                constraint,
+                column,
+                clusters,
+            } => write!(
+                f,
+                "column {} of constraint {} is in clusters {} and {}",
+                column, constraint, clusters.0, clusters.1
+            ),
+            Violation::Crossing {
+                constraint,
+                columns,
+                clusters,
+            } => write!(
+                f,
+                "columns {} and {} of constraint {} are in clusters {} and {}, out of order",
+                columns.0, columns.1, constraint, clusters.0, clusters.1
+            ),
+            Violation::Missing { constraint, column } => write!(
+                f,
+                "column {} of constraint {} has no output column",
+                column, constraint
+            ),
+            Violation::Misplaced {
+                constraint,
+                column,
+                output_column,
+            } => write!(
+                f,
+                "column {} of constraint {} is misplaced in output column {}",
+                column, constraint, output_column
+            ),
+            Violation::Width {
+                constraint,
+                width,
+                expected,
+            } => write!(
+                f,
+                "constraint {} spans {} output columns, expected {}",
+                constraint, width, expected
+            ),
+            Violation::MissingSequence { constraint, name } => write!(
+                f,
+                "sequence {:?} of constraint {} is missing from the merged alignment",
+                name, constraint
+            ),
+            Violation::UnknownSequence { name } => write!(
+                f,
+                "sequence {:?} of the merged alignment is not in any constraint",
+                name
+            ),
+            Violation::DuplicateSequence { name } => write!(
+                f,
+                "sequence {:?} appears more than once in the merged alignment",
+                name
+            ),
+            Violation::Ragged {
+                name,
+                width,
+                expected,
+            } => write!(
+                f,
+                "merged sequence {:?} has {} columns, expected {}",
+                name, width, expected
+            ),
+            Violation::ResidueMismatch {
+                constraint,
+                name,
+                position,
+            } => write!(
+                f,
+                "residues of merged sequence {:?} differ from constraint {} from residue {} on",
+                name, constraint, position
+            ),
+            Violation::Split {
+                constraint,
+                column,
+                output_columns,
+            } => write!(
+                f,
+                "column {} of constraint {} is split over output columns {} and {}",
+                column, constraint, output_columns.0, output_columns.1
+            ),
+            Violation::Reordered {
+                constraint,
+                columns,
+                output_columns,
+            } => write!(
+                f,
+                "columns {} and {} of constraint {} are in output columns {} and {}, out of order",
+                columns.0, columns.1, constraint, output_columns.0, output_columns.1
+            ),
+        }
+    }
+}
+
+/// All the violations found in a trace and its layout.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub violations: Vec<Violation>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} violation(s) found in the merged alignment:",
+            self.violations.len()
+        )?;
+        for v in &self.violations {
+            write!(f, "\n  {}", v)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VerifyReport {}
+
+/// Checks a trace and the frames laid out from it (as built by
+/// [`crate::merge::build_frames`]) against the columns of the constraints.
+pub fn verify_trace(state: &AlnState, res: &ClusteringResult, frames: &[Vec<u32>]) -> VerifyReport {
+    let mut violations = res.violations(&state.column_counts);
+    if violations.is_empty() {
+        violations.extend(frame_violations(state, res, frames));
+    }
+    VerifyReport { violations }
+}
+
+/// Checks that the frames place every column of the constraints in an output column, the
+/// columns of a cluster together and nothing else with them.
+fn frame_violations(
+    state: &AlnState,
+    res: &ClusteringResult,
+    frames: &[Vec<u32>],
+) -> Vec<Violation> {
+    let offsets = state.column_offsets();
+    let total =
+        offsets.last().copied().unwrap_or(0) + state.column_counts.last().copied().unwrap_or(0);
+    let mut cluster_of: Vec<Option<usize>> = vec![None; total];
+    for (x, cluster) in res.clusters.iter().enumerate() {
+        for &(c, i) in cluster {
+            cluster_of[offsets[c as usize] + i as usize] = Some(x);
+        }
+    }
+    let mut violations = vec![];
+    // what each output column holds: a cluster, or a single column numbered after the clusters
+    let mut owner: Vec<Option<usize>> = vec![];
+    let mut cluster_column: Vec<Option<usize>> = vec![None; res.clusters.len()];
+    let mut expected = None;
+    for (c, &count) in state.column_counts.iter().enumerate() {
+        let frame = frames.get(c).map_or(&[][..], |frame| &frame[..]);
+        let mut position = 0;
+        for i in 0..count {
+            let gaps = match frame.get(i) {
+                Some(&gaps) => gaps as usize,
+                None => {
+                    violations.push(Violation::Missing {
+                        constraint: c as u32,
+                        column: i as u32,
+                    });
+                    continue;
+                }
+            };
+            position += gaps;
+            let u = offsets[c] + i;
+            let tag = cluster_of[u].unwrap_or(res.clusters.len() + u);
+            if owner.len() <= position {
+                owner.resize(position + 1, None);
+            }
+            let apart = cluster_of[u].map_or(false, |x| {
+                *cluster_column[x].get_or_insert(position) != position
+            });
+            let shared = *owner[position].get_or_insert(tag) != tag;
+            if apart || shared {
+                violations.push(Violation::Misplaced {
+                    constraint: c as u32,
+                    column: i as u32,
+                    output_column: position,
+                });
+            }
+            position += 1;
+        }
+        let width = position + frame.get(count).map_or(0, |&gaps| gaps as usize);
+        match expected {
+            None => expected = Some(width),
+            Some(expected) if expected != width => violations.push(Violation::Width {
+                constraint: c as u32,
+                width,
+                expected,
+            }),
+            _ => {}
+        }
+    }
+    violations
+}
+
+/// Compares a merged alignment with the constraints it was merged from: every sequence must
+/// appear once with the same residues, and the merged alignment restricted to the sequences of
+/// each constraint must be that constraint column for column, up to all-gap columns.
+pub fn verify_merged(constraints: &[PathBuf], merged: &PathBuf) -> anyhow::Result<VerifyReport> {
+    let mut violations = vec![];
+    let mut rows: AHashMap<String, Vec<u8>> = AHashMap::default();
+    let mut expected = None;
+    let mut reader = open_fasta(merged)?;
+    while let Some(result) = reader.next() {
+        let rec = result.with_context(|| format!("reading {}", merged.display()))?;
+        let name = String::from_utf8(rec.head().to_vec())?;
+        let row: Vec<u8> = rec.seq_lines().flatten().copied().collect();
+        match expected {
+            None => expected = Some(row.len()),
+            Some(expected) if expected != row.len() => violations.push(Violation::Ragged {
+                name: name.clone(),
+                width: row.len(),
+                expected,
+            }),
+            _ => {}
+        }
+        if rows.insert(name.clone(), row).is_some() {
+            violations.push(Violation::DuplicateSequence { name });
+        }
+    }
+    for (c, constraint) in constraints.iter().enumerate() {
+        let c = c as u32;
+        // output column of each column of the constraint, and whether it is reported as split
+        let mut placed: Vec<Option<usize>> = vec![];
+        let mut split: Vec<bool> = vec![];
+        let mut reader = open_fasta(constraint)?;
+        while let Some(result) = reader.next() {
+            let rec = result.with_context(|| format!("reading {}", constraint.display()))?;
+            let name = String::from_utf8(rec.head().to_vec())?;
+            let row = match rows.remove(&name) {
+                Some(row) => row,
+                None => {
+                    violations.push(Violation::MissingSequence {
+                        constraint: c,
+                        name,
+                    });
+                    continue;
+                }
+            };
+            let mut output = row.iter().enumerate().filter(|&(_, &x)| x != b'-');
+            let mut residues = 0;
+            let mut mismatch = false;
+            for (i, &x) in rec.seq_lines().flatten().enumerate() {
+                if x == b'-' {
+                    continue;
+                }
+                residues += 1;
+                let j = match output.next() {
+                    Some((j, y)) if y.eq_ignore_ascii_case(&x) => j,
+                    _ => {
+                        mismatch = true;
+                        break;
+                    }
+                };
+                if placed.len() <= i {
+                    placed.resize(i + 1, None);
+                    split.resize(i + 1, false);
+                }
+                let first = *placed[i].get_or_insert(j);
+                if first != j && !split[i] {
+                    split[i] = true;
+                    violations.push(Violation::Split {
+                        constraint: c,
+                        column: i as u32,
+                        output_columns: (first, j),
+                    });
+                }
+            }
+            if mismatch || output.next().is_some() {
+                violations.push(Violation::ResidueMismatch {
+                    constraint: c,
+                    name,
+                    position: residues + usize::from(!mismatch),
+                });
+            }
+        }
+        let mut prev: Option<(usize, usize)> = None;
+        for (i, j) in placed
+            .iter()
+            .enumerate()
+            .filter_map(|(i, j)| j.map(|j| (i, j)))
+        {
+            if let Some((h, k)) = prev {
+                if k >= j {
+                    violations.push(Violation::Reordered {
+                        constraint: c,
+                        columns: (h as u32, i as u32),
+                        output_columns: (k, j),
+                    });
+                }
+            }
+            prev = Some((i, j));
+        }
+    }
+    let mut unknown: Vec<String> = rows.into_keys().collect();
+    unknown.sort_unstable();
+    violations.extend(
+        unknown
+            .into_iter()
+            .map(|name| Violation::UnknownSequence { name }),
+    );
+    Ok(VerifyReport { violations })
+}